@@ -0,0 +1,34 @@
+//! Criterion benchmarks with per-iteration allocation counts
+//!
+//! Run with: cargo alloc-profile bench -- --features criterion --bench allocations
+
+use cargo_alloc_profile::criterion::BenchAllocations;
+use criterion::{Criterion, criterion_group, criterion_main};
+
+fn bench_vec_growth(c: &mut Criterion) {
+    c.bench_allocations("vec_push", || {
+        let mut items = Vec::new();
+        for i in 0..100 {
+            items.push(i);
+        }
+        items
+    });
+
+    c.bench_allocations("vec_with_capacity", || {
+        let mut items = Vec::with_capacity(100);
+        for i in 0..100 {
+            items.push(i);
+        }
+        items
+    });
+}
+
+fn bench_strings(c: &mut Criterion) {
+    let mut group = c.benchmark_group("strings");
+    group.bench_allocations("format", || format!("Item {}", 42));
+    group.bench_allocations("to_string", || 42.to_string());
+    group.finish();
+}
+
+criterion_group!(benches, bench_vec_growth, bench_strings);
+criterion_main!(benches);
@@ -0,0 +1,1626 @@
+//! Recording core of `cargo-alloc-profile`.
+//!
+//! [`AllocationProfiler`] records allocations, builds snapshots, streams them
+//! to `cargo alloc-profile` and writes the report. It is shared by the
+//! `cargo-alloc-profile` library, whose global allocator feeds it, and the
+//! `LD_PRELOAD` interposer, which feeds it from the C allocator functions.
+//! It declares no global allocator of its own.
+
+use backtrace::Backtrace;
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// Global flag to enable/disable profiling - starts disabled
+static PROFILING_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Minimum time between two memory timeline samples, in microseconds
+static SAMPLE_INTERVAL_US: AtomicU64 = AtomicU64::new(100);
+
+// Marker for "no timeline sample taken yet"
+const NO_SAMPLE: u64 = u64::MAX;
+
+// Most timeline samples kept in memory. When the timeline is full, it keeps
+// the higher of every two samples and the sample interval doubles, so a long
+// run still covers its whole length in bounded memory.
+const MAX_TIMELINE_SAMPLES: usize = 1 << 16;
+
+// Allocations of at least this many bytes are recorded as timeline events
+static LARGE_ALLOCATION_BYTES: AtomicUsize = AtomicUsize::new(64 * 1024);
+
+// Frames of the profiler itself, always left out of recorded stacks
+const INTERNAL_FRAMES: &[&str] = &[
+    "backtrace",
+    "cargo_alloc_profile::allocator",
+    "cargo_alloc_profile_core",
+    "cargo_alloc_profile_preload",
+];
+
+// Standard library allocation internals left out of recorded stacks unless
+// kept explicitly: the `alloc` crate and the `__rust_alloc` family of shims
+// that call the global allocator, which newer toolchains put in `__rustc`
+const DEFAULT_SKIP_FRAMES: &[&str] = &[
+    "alloc",
+    "__rustc",
+    "__rust_alloc",
+    "__rust_alloc_zeroed",
+    "__rust_realloc",
+];
+
+// Whether the global allocator captures a stack for each allocation
+static CAPTURE_STACKS: AtomicBool = AtomicBool::new(true);
+
+// Connection to `cargo alloc-profile`, when running under it
+static STREAM: Mutex<Option<Stream>> = Mutex::new(None);
+static STREAM_STARTED: AtomicBool = AtomicBool::new(false);
+// Set in a forked child whose parent was connected, so the child connects too
+static RECONNECT: AtomicBool = AtomicBool::new(false);
+
+// Set once the report is written, so the crash handlers do not write another
+static REPORT_WRITTEN: AtomicBool = AtomicBool::new(false);
+// Set when the report is written by a crash handler rather than `write_report`
+static REPORT_PARTIAL: AtomicBool = AtomicBool::new(false);
+// Set by `write_report_at_exit`, so the report written on exit is complete
+static REPORT_AT_EXIT: AtomicBool = AtomicBool::new(false);
+
+// Source of the small per-thread ids used in timelines (0 means unassigned)
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+
+// Thread-local reentrancy guard - prevents infinite recursion
+thread_local! {
+    static IN_PROFILER: Cell<bool> = const { Cell::new(false) };
+    static THREAD_ID: Cell<u64> = const { Cell::new(0) };
+    // Paths of the spans entered on this thread, innermost last
+    static SPANS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    // Reentrancy flag of the forking thread from before it forked
+    static FORK_WAS_IN_PROFILER: Cell<bool> = const { Cell::new(false) };
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AllocationSite {
+    pub count: usize,
+    pub total_bytes: usize,
+    pub frames: Vec<String>,
+    /// Allocations from this site that have not been freed yet
+    #[serde(default)]
+    pub live_count: usize,
+    /// Bytes from this site that have not been freed yet
+    #[serde(default)]
+    pub live_bytes: usize,
+    /// Allocations from this site that were live when the heap peaked
+    #[serde(default)]
+    pub count_at_peak: usize,
+    /// Bytes from this site that were live when the heap peaked
+    #[serde(default)]
+    pub bytes_at_peak: usize,
+    /// Most bytes this site ever had live at once
+    #[serde(default)]
+    pub max_live_bytes: usize,
+    /// Allocations this site had live when `max_live_bytes` was reached
+    #[serde(default)]
+    pub max_live_count: usize,
+    /// Sum of the lifetimes of this site's allocations, counting live ones
+    /// up to the time of the snapshot
+    #[serde(default)]
+    pub total_lifetime_us: u64,
+    /// Path of the span that was active when these allocations were made,
+    /// such as `request > parse`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub span: Option<String>,
+    // The heap peak `count_at_peak` and `bytes_at_peak` were taken at
+    #[serde(skip)]
+    peak_epoch: u64,
+}
+
+impl AllocationSite {
+    /// The key that identifies this site: its stack and span
+    pub fn key(&self) -> String {
+        site_key(&self.frames, self.span.as_deref())
+    }
+
+    /// Fold another site's totals into this one. Maximums are summed, so
+    /// they are an upper bound for the merged site.
+    pub fn merge(&mut self, other: &AllocationSite) {
+        self.count += other.count;
+        self.total_bytes += other.total_bytes;
+        self.live_count += other.live_count;
+        self.live_bytes += other.live_bytes;
+        self.count_at_peak += other.count_at_peak;
+        self.bytes_at_peak += other.bytes_at_peak;
+        self.max_live_bytes += other.max_live_bytes;
+        self.max_live_count += other.max_live_count;
+        self.total_lifetime_us += other.total_lifetime_us;
+    }
+
+    // Take the live counts as the counts at the latest heap peak, unless
+    // they were already taken for it. A site's live counts only change when
+    // it is recorded, so doing this just before they change, or when a
+    // snapshot is taken, gives the counts it had when the heap peaked.
+    fn settle_peak(&mut self, epoch: u64) {
+        if self.peak_epoch != epoch {
+            self.count_at_peak = self.live_count;
+            self.bytes_at_peak = self.live_bytes;
+            self.peak_epoch = epoch;
+        }
+    }
+}
+
+/// Rules for turning a backtrace into the frames of an allocation site.
+///
+/// Patterns are symbol path prefixes matched on `::` boundaries, so `alloc`
+/// matches `alloc::vec::Vec<T>::push` and `<alloc::string::String as
+/// core::clone::Clone>::clone` but not `my_alloc::pool::get`.
+#[derive(Debug, Clone)]
+pub struct StackConfig {
+    /// Most frames kept per stack, counted from the allocation outwards
+    pub max_depth: usize,
+    /// Frames matching any of these are dropped
+    pub skip: Vec<String>,
+    /// Frames matching any of these are kept even if they match `skip`
+    pub keep: Vec<String>,
+}
+
+impl Default for StackConfig {
+    fn default() -> Self {
+        Self {
+            max_depth: 32,
+            skip: DEFAULT_SKIP_FRAMES.iter().map(|s| s.to_string()).collect(),
+            keep: Vec::new(),
+        }
+    }
+}
+
+impl StackConfig {
+    fn skips(&self, path: &str) -> bool {
+        self.skip.iter().any(|p| path_has_prefix(path, p))
+            && !self.keep.iter().any(|p| path_has_prefix(path, p))
+    }
+}
+
+/// Heap usage at a point in time, relative to when profiling was enabled
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct MemorySample {
+    pub time_us: u64,
+    pub current_memory: usize,
+}
+
+/// A single allocation above the large-allocation threshold
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LargeAllocation {
+    pub time_us: u64,
+    pub size: usize,
+    pub thread: u64,
+    pub frames: Vec<String>,
+}
+
+/// Allocations made by the iterations of one benchmark
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BenchmarkAllocations {
+    pub id: String,
+    pub iterations: u64,
+    pub allocations: u64,
+    pub bytes: u64,
+}
+
+/// A tracked allocation taken out of the live allocations by
+/// [`AllocationProfiler::detach`]
+pub struct Detached {
+    address: usize,
+    live: LiveAllocation,
+}
+
+impl Detached {
+    /// Record the deallocation of the detached block
+    pub fn release(self) {
+        if let Some(_guard) = ReentrancyGuard::enter() {
+            AllocationProfiler::released(self.live, None);
+        }
+    }
+
+    /// Track the block again, as it was not freed after all
+    pub fn restore(self) {
+        if let Some(_guard) = ReentrancyGuard::enter() {
+            PROFILER
+                .live_allocations
+                .lock()
+                .insert(self.address, self.live);
+        }
+    }
+}
+
+// A tracked allocation that is still live, keyed by its address
+struct LiveAllocation {
+    site_key: String,
+    size: usize,
+    time_us: u64,
+}
+
+pub struct ProfilerData {
+    pub total_allocations: AtomicUsize,
+    pub total_deallocations: AtomicUsize,
+    pub total_bytes_allocated: AtomicUsize,
+    pub peak_memory: AtomicUsize,
+    pub current_memory: AtomicUsize,
+    pub allocation_sites: Mutex<HashMap<String, AllocationSite>>,
+    live_allocations: Mutex<HashMap<usize, LiveAllocation>>,
+    started: OnceCell<Instant>,
+    // When profiling started, in microseconds since the Unix epoch
+    start_time_us: AtomicU64,
+    peak_time_us: AtomicU64,
+    // Bumped, with the sites locked, whenever the heap reaches a new peak
+    peak_epoch: AtomicU64,
+    last_sample_us: AtomicU64,
+    timeline: Mutex<Vec<MemorySample>>,
+    large_allocations: Mutex<Vec<LargeAllocation>>,
+    threads: Mutex<HashMap<u64, String>>,
+    stack_config: RwLock<StackConfig>,
+    benchmarks: Mutex<Vec<BenchmarkAllocations>>,
+}
+
+static PROFILER: Lazy<ProfilerData> = Lazy::new(|| ProfilerData {
+    total_allocations: AtomicUsize::new(0),
+    total_deallocations: AtomicUsize::new(0),
+    total_bytes_allocated: AtomicUsize::new(0),
+    peak_memory: AtomicUsize::new(0),
+    current_memory: AtomicUsize::new(0),
+    allocation_sites: Mutex::new(HashMap::new()),
+    live_allocations: Mutex::new(HashMap::new()),
+    started: OnceCell::new(),
+    start_time_us: AtomicU64::new(0),
+    peak_time_us: AtomicU64::new(0),
+    peak_epoch: AtomicU64::new(0),
+    last_sample_us: AtomicU64::new(NO_SAMPLE),
+    timeline: Mutex::new(Vec::new()),
+    large_allocations: Mutex::new(Vec::new()),
+    threads: Mutex::new(HashMap::new()),
+    stack_config: RwLock::new(StackConfig::default()),
+    benchmarks: Mutex::new(Vec::new()),
+});
+
+pub struct AllocationProfiler;
+impl AllocationProfiler {
+    pub fn record_allocation(size: usize, backtrace: Backtrace) {
+        Self::record(None, size, backtrace);
+    }
+
+    /// Record an allocation at a known address so its site can be credited
+    /// when it is freed (see [`AllocationProfiler::record_deallocation_at`])
+    pub fn record_allocation_at(address: usize, size: usize, backtrace: Backtrace) {
+        Self::record(Some(address), size, backtrace);
+    }
+
+    fn record(address: Option<usize>, size: usize, mut backtrace: Backtrace) {
+        // Quick atomic check (no allocation)
+        if !PROFILING_ACTIVE.load(Ordering::Relaxed) {
+            return;
+        }
+
+        // Check for reentrancy - prevent infinite recursion
+        let Some(_guard) = ReentrancyGuard::enter() else {
+            return;
+        };
+
+        reconnect_after_fork();
+
+        // Update global counters
+        PROFILER.total_allocations.fetch_add(1, Ordering::Relaxed);
+        PROFILER
+            .total_bytes_allocated
+            .fetch_add(size, Ordering::Relaxed);
+
+        let new_current = PROFILER.current_memory.fetch_add(size, Ordering::Relaxed) + size;
+
+        // Update peak memory
+        let mut peak = PROFILER.peak_memory.load(Ordering::Relaxed);
+        let mut new_peak = false;
+        while new_current > peak {
+            match PROFILER.peak_memory.compare_exchange_weak(
+                peak,
+                new_current,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    new_peak = true;
+                    break;
+                }
+                Err(x) => peak = x,
+            }
+        }
+
+        let now_us = elapsed_us();
+        let thread = current_thread_id();
+        Self::record_sample(now_us, new_current);
+
+        // Resolve backtrace and record allocation site
+        backtrace.resolve();
+        let frames = extract_frames(&backtrace);
+
+        if size >= LARGE_ALLOCATION_BYTES.load(Ordering::Relaxed) {
+            PROFILER.large_allocations.lock().push(LargeAllocation {
+                time_us: now_us,
+                size,
+                thread,
+                frames: frames.clone(),
+            });
+        }
+
+        // Stacks are empty when stack capture is off; such allocations are
+        // still tracked, so their deallocations are counted
+        let span = current_span();
+        let key = site_key(&frames, span.as_deref());
+        if !frames.is_empty() {
+            let tracked = address.is_some();
+
+            {
+                let mut sites = PROFILER.allocation_sites.lock();
+                let epoch = PROFILER.peak_epoch.load(Ordering::Relaxed);
+                let site = sites.entry(key.clone()).or_insert_with(|| AllocationSite {
+                    count: 0,
+                    total_bytes: 0,
+                    frames,
+                    live_count: 0,
+                    live_bytes: 0,
+                    count_at_peak: 0,
+                    bytes_at_peak: 0,
+                    max_live_bytes: 0,
+                    max_live_count: 0,
+                    total_lifetime_us: 0,
+                    span,
+                    peak_epoch: epoch,
+                });
+                site.settle_peak(epoch);
+                site.count += 1;
+                site.total_bytes += size;
+                if tracked {
+                    site.live_count += 1;
+                    site.live_bytes += size;
+                    if site.live_bytes > site.max_live_bytes {
+                        site.max_live_bytes = site.live_bytes;
+                        site.max_live_count = site.live_count;
+                    }
+                }
+
+                // Other sites take their counts at this peak lazily, see
+                // `AllocationSite::settle_peak`
+                if new_peak && tracked {
+                    PROFILER.peak_epoch.store(epoch + 1, Ordering::Relaxed);
+                    site.settle_peak(epoch + 1);
+                    PROFILER.peak_time_us.store(now_us, Ordering::Relaxed);
+                }
+            }
+        }
+
+        // Locks are taken one at a time so deallocation can look up the live
+        // map first without risking a lock-order inversion
+        if let Some(address) = address {
+            PROFILER.live_allocations.lock().insert(
+                address,
+                LiveAllocation {
+                    site_key: key,
+                    size,
+                    time_us: now_us,
+                },
+            );
+        }
+    }
+
+    pub fn record_deallocation(size: usize) {
+        // Only record if profiling is active
+        if !PROFILING_ACTIVE.load(Ordering::Relaxed) {
+            return;
+        }
+
+        PROFILER.total_deallocations.fetch_add(1, Ordering::Relaxed);
+        PROFILER.current_memory.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    /// Record a deallocation and release the live bytes of the site that
+    /// allocated `address`. Addresses that were not recorded with
+    /// `record_allocation_at`, such as memory allocated before profiling
+    /// started or by the profiler itself, are ignored.
+    pub fn record_deallocation_at(address: usize, size: usize) {
+        Self::release(address, Some(size));
+    }
+
+    /// Record the deallocation of `address` when the caller does not know its
+    /// size, as with C `free`
+    pub fn record_free(address: usize) {
+        Self::release(address, None);
+    }
+
+    fn release(address: usize, size: Option<usize>) {
+        if !PROFILING_ACTIVE.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let Some(_guard) = ReentrancyGuard::enter() else {
+            return;
+        };
+
+        let live = PROFILER.live_allocations.lock().remove(&address);
+        if let Some(live) = live {
+            Self::released(live, size);
+        }
+    }
+
+    /// Stop tracking `address` without recording its deallocation yet. A
+    /// reallocation frees the old block itself, after which the address may
+    /// be handed out to another thread, so the global allocator and the
+    /// interposer detach the block first and then [`Detached::release`] or
+    /// [`Detached::restore`] it depending on the outcome.
+    pub fn detach(address: usize) -> Option<Detached> {
+        if !PROFILING_ACTIVE.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let _guard = ReentrancyGuard::enter()?;
+        let live = PROFILER.live_allocations.lock().remove(&address)?;
+        Some(Detached { address, live })
+    }
+
+    // Record the deallocation of a block taken out of the live map. Must be
+    // called with the reentrancy guard held.
+    fn released(live: LiveAllocation, size: Option<usize>) {
+        Self::record_deallocation(size.unwrap_or(live.size));
+
+        let now_us = elapsed_us();
+        Self::record_sample(now_us, PROFILER.current_memory.load(Ordering::Relaxed));
+
+        let mut sites = PROFILER.allocation_sites.lock();
+        if let Some(site) = sites.get_mut(&live.site_key) {
+            site.settle_peak(PROFILER.peak_epoch.load(Ordering::Relaxed));
+            site.live_count = site.live_count.saturating_sub(1);
+            site.live_bytes = site.live_bytes.saturating_sub(live.size);
+            site.total_lifetime_us += now_us.saturating_sub(live.time_us);
+        }
+    }
+
+    // Append a timeline sample if the sample interval has passed. Must be
+    // called with the reentrancy guard held, since it may allocate.
+    fn record_sample(now_us: u64, current_memory: usize) {
+        let due = |last: u64| {
+            last == NO_SAMPLE
+                || now_us >= last.saturating_add(SAMPLE_INTERVAL_US.load(Ordering::Relaxed))
+        };
+        if !due(PROFILER.last_sample_us.load(Ordering::Relaxed)) {
+            return;
+        }
+
+        // Checked again under the lock, so samples are appended in time order
+        let mut timeline = PROFILER.timeline.lock();
+        if !due(PROFILER.last_sample_us.load(Ordering::Relaxed)) {
+            return;
+        }
+        PROFILER.last_sample_us.store(now_us, Ordering::Relaxed);
+
+        if timeline.len() >= MAX_TIMELINE_SAMPLES {
+            let kept: Vec<MemorySample> = timeline
+                .chunks(2)
+                .map(|pair| {
+                    *pair
+                        .iter()
+                        .max_by_key(|sample| sample.current_memory)
+                        .unwrap()
+                })
+                .collect();
+            *timeline = kept;
+            SAMPLE_INTERVAL_US.store(
+                SAMPLE_INTERVAL_US.load(Ordering::Relaxed).max(1) * 2,
+                Ordering::Relaxed,
+            );
+        }
+        timeline.push(MemorySample {
+            time_us: now_us,
+            current_memory,
+        });
+    }
+
+    pub fn get_snapshot() -> ProfileSnapshot {
+        let now_us = elapsed_us();
+
+        // Allocations that are still live count towards lifetimes up to now
+        let live_lifetimes = live_lifetimes(now_us);
+        let mut sites = settled_sites(&PROFILER.allocation_sites.lock());
+        for (key, lifetime) in live_lifetimes {
+            if let Some(site) = sites.get_mut(&key) {
+                site.total_lifetime_us += lifetime;
+            }
+        }
+
+        let current_memory = PROFILER.current_memory.load(Ordering::Relaxed);
+
+        // Close the timeline with the state at the time of the snapshot
+        let mut timeline = PROFILER.timeline.lock().clone();
+        timeline.push(MemorySample {
+            time_us: now_us,
+            current_memory,
+        });
+
+        ProfileSnapshot {
+            total_allocations: PROFILER.total_allocations.load(Ordering::Relaxed),
+            total_deallocations: PROFILER.total_deallocations.load(Ordering::Relaxed),
+            total_bytes_allocated: PROFILER.total_bytes_allocated.load(Ordering::Relaxed),
+            peak_memory: PROFILER.peak_memory.load(Ordering::Relaxed),
+            current_memory,
+            allocation_sites: sites,
+            pid: std::process::id(),
+            parent_pid: parent_pid(),
+            start_time_us: PROFILER.start_time_us.load(Ordering::Relaxed),
+            partial: REPORT_PARTIAL.load(Ordering::Relaxed),
+            command: command_line(),
+            peak_time_us: PROFILER.peak_time_us.load(Ordering::Relaxed),
+            timeline,
+            large_allocations: PROFILER.large_allocations.lock().clone(),
+            threads: PROFILER.threads.lock().clone(),
+            benchmarks: PROFILER.benchmarks.lock().clone(),
+            processes: Vec::new(),
+        }
+    }
+
+    /// Total number of allocations and bytes recorded so far
+    pub fn allocation_counts() -> (usize, usize) {
+        (
+            PROFILER.total_allocations.load(Ordering::Relaxed),
+            PROFILER.total_bytes_allocated.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Add the allocations made by `iterations` runs of a benchmark to its
+    /// totals
+    pub fn record_benchmark(id: &str, iterations: u64, allocations: u64, bytes: u64) {
+        without_profiling(|| {
+            let mut benchmarks = PROFILER.benchmarks.lock();
+            match benchmarks.iter_mut().find(|benchmark| benchmark.id == id) {
+                Some(benchmark) => {
+                    benchmark.iterations += iterations;
+                    benchmark.allocations += allocations;
+                    benchmark.bytes += bytes;
+                }
+                None => benchmarks.push(BenchmarkAllocations {
+                    id: id.to_string(),
+                    iterations,
+                    allocations,
+                    bytes,
+                }),
+            }
+        });
+    }
+
+    /// Enable allocation profiling
+    ///
+    /// Timeline settings are read from `CARGO_ALLOC_PROFILE_SAMPLE_INTERVAL_US`
+    /// and `CARGO_ALLOC_PROFILE_LARGE_ALLOC_BYTES` when they are set. Under
+    /// `cargo alloc-profile`, this also connects to it through
+    /// `CARGO_ALLOC_PROFILE_SOCKET` and starts streaming updates.
+    pub fn enable() {
+        // Read settings before activating, as reading the environment allocates
+        let socket = std::env::var("CARGO_ALLOC_PROFILE_SOCKET").ok();
+        if let Some(interval) = env_setting("CARGO_ALLOC_PROFILE_SAMPLE_INTERVAL_US") {
+            SAMPLE_INTERVAL_US.store(interval, Ordering::Relaxed);
+        }
+        if let Some(threshold) = env_setting("CARGO_ALLOC_PROFILE_LARGE_ALLOC_BYTES") {
+            LARGE_ALLOCATION_BYTES.store(threshold as usize, Ordering::Relaxed);
+        }
+        without_profiling(|| {
+            let mut config = PROFILER.stack_config.write();
+            if let Some(depth) = env_setting("CARGO_ALLOC_PROFILE_MAX_DEPTH") {
+                config.max_depth = depth as usize;
+            }
+            // Replaced rather than extended, so enabling again doesn't
+            // repeat them
+            if let Some(skip) = env_list("CARGO_ALLOC_PROFILE_SKIP_FRAMES") {
+                config.skip = DEFAULT_SKIP_FRAMES.iter().map(|s| s.to_string()).collect();
+                config.skip.extend(skip);
+            }
+            if let Some(keep) = env_list("CARGO_ALLOC_PROFILE_KEEP_FRAMES") {
+                config.keep = keep;
+            }
+        });
+        PROFILER.started.get_or_init(|| {
+            let since_epoch = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default();
+            PROFILER
+                .start_time_us
+                .store(since_epoch.as_micros() as u64, Ordering::Relaxed);
+            Instant::now()
+        });
+
+        #[cfg(unix)]
+        without_profiling(register_fork_handlers);
+        without_profiling(install_crash_handlers);
+
+        PROFILING_ACTIVE.store(true, Ordering::Relaxed);
+
+        if let Some(address) = socket {
+            without_profiling(|| start_stream(&address));
+        }
+    }
+
+    /// Replace the rules used to capture allocation stacks
+    pub fn set_stack_config(config: StackConfig) {
+        without_profiling(|| *PROFILER.stack_config.write() = config);
+    }
+
+    /// The rules currently used to capture allocation stacks
+    pub fn stack_config() -> StackConfig {
+        without_profiling(|| PROFILER.stack_config.read().clone())
+    }
+
+    /// Allocation totals of the benchmarks recorded so far
+    pub fn benchmarks() -> Vec<BenchmarkAllocations> {
+        without_profiling(|| PROFILER.benchmarks.lock().clone())
+    }
+
+    /// Enter a span on the current thread. Allocations are tagged with the
+    /// path of the innermost entered span until it is exited.
+    pub fn enter_span(name: &str) {
+        without_profiling(|| {
+            let _ = SPANS.try_with(|spans| {
+                let mut spans = spans.borrow_mut();
+                let path = match spans.last() {
+                    Some(parent) => format!("{} > {}", parent, name),
+                    None => name.to_string(),
+                };
+                spans.push(path);
+            });
+        });
+    }
+
+    /// Exit the innermost span entered on the current thread
+    pub fn exit_span() {
+        without_profiling(|| {
+            let _ = SPANS.try_with(|spans| spans.borrow_mut().pop());
+        });
+    }
+
+    /// Whether allocation profiling is enabled
+    pub fn is_enabled() -> bool {
+        PROFILING_ACTIVE.load(Ordering::Relaxed)
+    }
+
+    /// Turn stack capture in the global allocator on or off. Without stacks,
+    /// allocations are only counted, which is much cheaper.
+    pub fn set_capture_stacks(capture: bool) {
+        CAPTURE_STACKS.store(capture, Ordering::Relaxed);
+    }
+
+    /// Whether the global allocator captures a stack for each allocation
+    pub fn captures_stacks() -> bool {
+        CAPTURE_STACKS.load(Ordering::Relaxed)
+    }
+
+    /// Disable allocation profiling
+    pub fn disable() {
+        PROFILING_ACTIVE.store(false, Ordering::Relaxed);
+    }
+
+    /// Finish the profiling report: send the final update to
+    /// `cargo alloc-profile`, and write the whole report to
+    /// `CARGO_ALLOC_PROFILE_OUTPUT` if it is set. A `%p` in that path is
+    /// replaced with the process id, so that child processes inheriting it
+    /// each write their own report.
+    ///
+    /// If the program exits, aborts on a panic or is stopped by SIGTERM or
+    /// SIGINT before calling this, the report is written anyway and marked
+    /// partial.
+    pub fn write_report() {
+        finish_report(false);
+    }
+
+    /// Have the program write the complete report when it exits, for
+    /// profiling a program that does not call `write_report` itself. A panic
+    /// that aborts or a signal still leaves a partial report.
+    pub fn write_report_at_exit() {
+        REPORT_AT_EXIT.store(true, Ordering::Relaxed);
+    }
+}
+
+fn finish_report(partial: bool) {
+    REPORT_WRITTEN.store(true, Ordering::Relaxed);
+    REPORT_PARTIAL.store(partial, Ordering::Relaxed);
+
+    // Disable profiling during report generation
+    AllocationProfiler::disable();
+
+    without_profiling(reconnect_after_fork);
+    flush_stream(true);
+
+    if let Ok(output_path) = std::env::var("CARGO_ALLOC_PROFILE_OUTPUT") {
+        let output_path = output_path.replace("%p", &std::process::id().to_string());
+        let snapshot = AllocationProfiler::get_snapshot();
+        if let Ok(json) = serde_json::to_string(&snapshot) {
+            let _ = std::fs::write(&output_path, json);
+        }
+    }
+}
+
+// Write a partial report when the program ends without `write_report`: on
+// exit, including `process::exit`, on a panic and on SIGTERM or SIGINT
+fn install_crash_handlers() {
+    static INSTALLED: std::sync::Once = std::sync::Once::new();
+    INSTALLED.call_once(|| {
+        // SAFETY: the callback is a function that lives as long as the program
+        unsafe {
+            libc::atexit(report_at_exit);
+        }
+
+        let previous = std::panic::take_hook();
+        // The previous hook runs unprofiled, as printing a backtrace
+        // allocates heavily to symbolize it. Nothing is reported if the panic
+        // comes from the profiler itself, which may hold its locks.
+        std::panic::set_hook(Box::new(move |info| {
+            let in_profiler = IN_PROFILER.with(Cell::get);
+            without_profiling(|| previous(info));
+            if !in_profiler {
+                report_panic();
+            }
+        }));
+
+        #[cfg(unix)]
+        install_signal_handlers();
+    });
+}
+
+extern "C" fn report_at_exit() {
+    if !REPORT_WRITTEN.load(Ordering::Relaxed) {
+        finish_report(!REPORT_AT_EXIT.load(Ordering::Relaxed));
+    }
+}
+
+// A panic that unwinds out of `main` ends in a normal exit, and one on another
+// thread may be caught, so only an update is sent. A panic that aborts ends
+// the process without an exit, so the report is written now.
+fn report_panic() {
+    if cfg!(panic = "abort") {
+        if !REPORT_WRITTEN.load(Ordering::Relaxed) {
+            finish_report(true);
+        }
+    } else {
+        flush_stream(false);
+    }
+}
+
+// Signals that end the program with a report, unless it handles them itself
+#[cfg(unix)]
+const REPORTED_SIGNALS: [libc::c_int; 2] = [libc::SIGTERM, libc::SIGINT];
+
+// Write end of the pipe through which the signal handler wakes the thread
+// that writes the report
+#[cfg(unix)]
+static SIGNAL_PIPE: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(-1);
+
+// Writing the report allocates and takes locks, which is not safe in a signal
+// handler, so the handler only wakes a thread that writes it and then ends
+// the process with the same signal
+#[cfg(unix)]
+fn install_signal_handlers() {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for both ends of the pipe
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return;
+    }
+    for fd in fds {
+        // SAFETY: `fd` was just opened
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    let [reader, writer] = fds;
+
+    let spawned = std::thread::Builder::new()
+        .name("alloc-profile-signal".to_string())
+        .spawn(move || {
+            let mut signal = 0u8;
+            loop {
+                // SAFETY: reads a single byte into `signal`
+                match unsafe { libc::read(reader, (&mut signal as *mut u8).cast(), 1) } {
+                    1 => break,
+                    -1 if std::io::Error::last_os_error().kind()
+                        == std::io::ErrorKind::Interrupted => {}
+                    _ => return,
+                }
+            }
+            if !REPORT_WRITTEN.load(Ordering::Relaxed) {
+                finish_report(true);
+            }
+            // SAFETY: restores the default action of the signal, which ends
+            // the process, and raises it again
+            unsafe {
+                libc::signal(signal as libc::c_int, libc::SIG_DFL);
+                libc::raise(signal as libc::c_int);
+            }
+        });
+    if spawned.is_err() {
+        return;
+    }
+    SIGNAL_PIPE.store(writer, Ordering::Relaxed);
+
+    for signal in REPORTED_SIGNALS {
+        // SAFETY: the handler only writes to a pipe, which is async-signal-safe
+        let previous = unsafe { libc::signal(signal, signal_handler()) };
+        if previous != libc::SIG_DFL {
+            // SAFETY: puts back the handler the program installed
+            unsafe { libc::signal(signal, previous) };
+        }
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_signal(signal: libc::c_int) {
+    let byte = signal as u8;
+    // SAFETY: `write` is async-signal-safe
+    unsafe {
+        libc::write(
+            SIGNAL_PIPE.load(Ordering::Relaxed),
+            (&byte as *const u8).cast(),
+            1,
+        )
+    };
+}
+
+#[cfg(unix)]
+fn signal_handler() -> libc::sighandler_t {
+    on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t
+}
+
+// The thread that writes the report on a signal is not copied into a forked
+// child, so the child goes back to the default actions
+#[cfg(unix)]
+fn restore_default_signals() {
+    for signal in REPORTED_SIGNALS {
+        // SAFETY: only the handler installed by the profiler is replaced
+        let previous = unsafe { libc::signal(signal, libc::SIG_DFL) };
+        if previous != signal_handler() {
+            // SAFETY: puts back the handler the program installed
+            unsafe { libc::signal(signal, previous) };
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProfileSnapshot {
+    pub total_allocations: usize,
+    pub total_deallocations: usize,
+    pub total_bytes_allocated: usize,
+    pub peak_memory: usize,
+    pub current_memory: usize,
+    pub allocation_sites: HashMap<String, AllocationSite>,
+    /// Process id of the profiled program
+    #[serde(default)]
+    pub pid: u32,
+    /// Process id of the process that started it
+    #[serde(default)]
+    pub parent_pid: u32,
+    /// When profiling was enabled, in microseconds since the Unix epoch.
+    /// Times in the timeline are relative to it.
+    #[serde(default)]
+    pub start_time_us: u64,
+    /// Whether the program ended without writing its report, so the report
+    /// was written on its way out or is its last update
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+    /// Command line of the profiled program
+    #[serde(default)]
+    pub command: String,
+    /// When the heap reached `peak_memory`, relative to when profiling was enabled
+    #[serde(default)]
+    pub peak_time_us: u64,
+    /// Heap usage over time
+    #[serde(default)]
+    pub timeline: Vec<MemorySample>,
+    /// Allocations above the large-allocation threshold, in order
+    #[serde(default)]
+    pub large_allocations: Vec<LargeAllocation>,
+    /// Names of the threads that allocated, keyed by timeline thread id
+    #[serde(default)]
+    pub threads: HashMap<u64, String>,
+    /// Per-benchmark allocation totals, in the order the benchmarks ran
+    #[serde(default)]
+    pub benchmarks: Vec<BenchmarkAllocations>,
+    /// The processes combined into this snapshot, when there are several
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub processes: Vec<ProcessSummary>,
+}
+
+/// Totals of one process in a snapshot combined from several
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessSummary {
+    pub pid: u32,
+    pub parent_pid: u32,
+    pub command: String,
+    pub total_allocations: usize,
+    pub total_bytes_allocated: usize,
+    pub peak_memory: usize,
+}
+
+impl ProfileSnapshot {
+    /// Fold the changes of a streamed update into this snapshot
+    pub fn apply(&mut self, delta: ProfileDelta) {
+        self.total_allocations = delta.total_allocations;
+        self.total_deallocations = delta.total_deallocations;
+        self.total_bytes_allocated = delta.total_bytes_allocated;
+        self.peak_memory = delta.peak_memory;
+        self.current_memory = delta.current_memory;
+        self.peak_time_us = delta.peak_time_us;
+        for site in delta.sites {
+            self.allocation_sites.insert(site.key(), site);
+        }
+        self.timeline.extend(delta.timeline);
+        self.large_allocations.extend(delta.large_allocations);
+        self.threads.extend(delta.threads);
+        if let Some(benchmarks) = delta.benchmarks {
+            self.benchmarks = benchmarks;
+        }
+        self.partial |= delta.partial;
+    }
+
+    /// Combine the profiles of the processes of one program into a single
+    /// snapshot listing each of them. Sites and totals are summed; as the
+    /// processes may not have peaked at the same time, the combined peak is
+    /// an upper bound. The timeline is that of the first process.
+    pub fn combine(snapshots: Vec<ProfileSnapshot>) -> ProfileSnapshot {
+        let mut snapshots = snapshots.into_iter();
+        let Some(mut combined) = snapshots.next() else {
+            return ProfileSnapshot::default();
+        };
+        let mut processes = vec![ProcessSummary::of(&combined)];
+
+        for snapshot in snapshots {
+            processes.push(ProcessSummary::of(&snapshot));
+            combined.total_allocations += snapshot.total_allocations;
+            combined.total_deallocations += snapshot.total_deallocations;
+            combined.total_bytes_allocated += snapshot.total_bytes_allocated;
+            combined.peak_memory += snapshot.peak_memory;
+            combined.current_memory += snapshot.current_memory;
+            for (key, site) in snapshot.allocation_sites {
+                match combined.allocation_sites.get_mut(&key) {
+                    Some(existing) => existing.merge(&site),
+                    None => {
+                        combined.allocation_sites.insert(key, site);
+                    }
+                }
+            }
+            combined.benchmarks.extend(snapshot.benchmarks);
+            combined.partial |= snapshot.partial;
+        }
+
+        if processes.len() > 1 {
+            combined.processes = processes;
+        }
+        combined
+    }
+}
+
+impl ProcessSummary {
+    fn of(snapshot: &ProfileSnapshot) -> ProcessSummary {
+        ProcessSummary {
+            pid: snapshot.pid,
+            parent_pid: snapshot.parent_pid,
+            command: snapshot.command.clone(),
+            total_allocations: snapshot.total_allocations,
+            total_bytes_allocated: snapshot.total_bytes_allocated,
+            peak_memory: snapshot.peak_memory,
+        }
+    }
+}
+
+/// One line of JSON sent by a profiled program to `cargo alloc-profile`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamMessage {
+    /// Sent once, when profiling is enabled or a profiled process forks
+    Start {
+        pid: u32,
+        #[serde(default)]
+        parent_pid: u32,
+        #[serde(default)]
+        start_time_us: u64,
+        command: String,
+    },
+    /// Changes to the profile since the previous update
+    Update(ProfileDelta),
+    /// The last changes, sent with the final report
+    Finish(ProfileDelta),
+}
+
+/// Changes to a profile since the previous update. Totals are current
+/// values; sites are sent whole when any of their totals changed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProfileDelta {
+    pub total_allocations: usize,
+    pub total_deallocations: usize,
+    pub total_bytes_allocated: usize,
+    pub peak_memory: usize,
+    pub current_memory: usize,
+    pub peak_time_us: u64,
+    /// Sites that are new or changed
+    pub sites: Vec<AllocationSite>,
+    /// Timeline samples taken since the previous update
+    pub timeline: Vec<MemorySample>,
+    /// Large allocations made since the previous update
+    pub large_allocations: Vec<LargeAllocation>,
+    /// Threads named since the previous update
+    pub threads: HashMap<u64, String>,
+    /// All benchmark totals, if any changed
+    pub benchmarks: Option<Vec<BenchmarkAllocations>>,
+    /// Set on the final update when it was sent by a crash handler
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+}
+
+// Connect to `cargo alloc-profile` at `address` and send it the changes to
+// the profile every `CARGO_ALLOC_PROFILE_INTERVAL_MS` from a background
+// thread. Must be called with the reentrancy guard held.
+fn start_stream(address: &str) {
+    if STREAM_STARTED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let Some(writer) = connect(address) else {
+        return;
+    };
+    let mut stream = Stream {
+        writer,
+        sites: HashMap::new(),
+        timeline: None,
+        large_allocations: 0,
+        threads: 0,
+        benchmarks: Vec::new(),
+        totals: [0; 6],
+    };
+    if !stream.send(&StreamMessage::Start {
+        pid: std::process::id(),
+        parent_pid: parent_pid(),
+        start_time_us: PROFILER.start_time_us.load(Ordering::Relaxed),
+        command: command_line(),
+    }) {
+        return;
+    }
+    *STREAM.lock() = Some(stream);
+
+    let interval = Duration::from_millis(
+        env_setting("CARGO_ALLOC_PROFILE_INTERVAL_MS")
+            .unwrap_or(100)
+            .max(10),
+    );
+    let _ = std::thread::Builder::new()
+        .name("alloc-profile-stream".to_string())
+        .spawn(move || {
+            // Nothing this thread allocates is recorded
+            IN_PROFILER.with(|flag| flag.set(true));
+            loop {
+                std::thread::sleep(interval);
+                if !flush_stream(false) {
+                    break;
+                }
+            }
+        });
+}
+
+#[cfg(unix)]
+fn connect(address: &str) -> Option<Box<dyn Write + Send>> {
+    let stream = std::os::unix::net::UnixStream::connect(address).ok()?;
+    Some(Box::new(stream))
+}
+
+#[cfg(not(unix))]
+fn connect(address: &str) -> Option<Box<dyn Write + Send>> {
+    let stream = std::net::TcpStream::connect(address).ok()?;
+    let _ = stream.set_nodelay(true);
+    Some(Box::new(stream))
+}
+
+// Send the changes since the last update, or the final update that ends the
+// stream. Returns false once the stream is closed.
+fn flush_stream(finish: bool) -> bool {
+    without_profiling(|| {
+        let mut guard = STREAM.lock();
+        let Some(stream) = guard.as_mut() else {
+            return false;
+        };
+        let message = if finish {
+            StreamMessage::Finish(stream.delta(true))
+        } else {
+            match stream.delta(false) {
+                delta if stream.unchanged(&delta) => return true,
+                delta => StreamMessage::Update(delta),
+            }
+        };
+        let sent = stream.send(&message);
+        if !sent || finish {
+            *guard = None;
+        }
+        sent
+    })
+}
+
+// Connection to `cargo alloc-profile` and what has been sent over it so far
+struct Stream {
+    writer: Box<dyn Write + Send>,
+    sites: HashMap<String, SiteCounters>,
+    // Time of the last timeline sample sent, as the timeline may be thinned
+    // out between updates
+    timeline: Option<u64>,
+    large_allocations: usize,
+    threads: usize,
+    benchmarks: Vec<BenchmarkAllocations>,
+    // Totals of the last update sent
+    totals: [u64; 6],
+}
+
+// The totals of a site, to tell whether it changed since it was last sent
+type SiteCounters = [u64; 9];
+
+fn site_counters(site: &AllocationSite) -> SiteCounters {
+    [
+        site.count as u64,
+        site.total_bytes as u64,
+        site.live_count as u64,
+        site.live_bytes as u64,
+        site.count_at_peak as u64,
+        site.bytes_at_peak as u64,
+        site.max_live_bytes as u64,
+        site.max_live_count as u64,
+        site.total_lifetime_us,
+    ]
+}
+
+impl Stream {
+    // Must be called with the reentrancy guard held
+    fn send(&mut self, message: &StreamMessage) -> bool {
+        let Ok(mut line) = serde_json::to_vec(message) else {
+            return false;
+        };
+        line.push(b'\n');
+        self.writer.write_all(&line).is_ok()
+    }
+
+    // Whether `delta` has nothing new, so an idle process need not send it.
+    // Otherwise its totals are the last ones sent from now on.
+    fn unchanged(&mut self, delta: &ProfileDelta) -> bool {
+        let totals = [
+            delta.total_allocations as u64,
+            delta.total_deallocations as u64,
+            delta.total_bytes_allocated as u64,
+            delta.peak_memory as u64,
+            delta.current_memory as u64,
+            delta.peak_time_us,
+        ];
+        let unchanged = totals == self.totals
+            && delta.sites.is_empty()
+            && delta.timeline.is_empty()
+            && delta.large_allocations.is_empty()
+            && delta.threads.is_empty()
+            && delta.benchmarks.is_none();
+        self.totals = totals;
+        unchanged
+    }
+
+    // The changes since the last update. The final one also counts live
+    // allocations towards lifetimes and closes the timeline, so applying
+    // every update in turn gives the same result as `get_snapshot`.
+    fn delta(&mut self, finish: bool) -> ProfileDelta {
+        let now_us = elapsed_us();
+        let live_lifetimes = if finish {
+            live_lifetimes(now_us)
+        } else {
+            HashMap::new()
+        };
+
+        let mut sites = Vec::new();
+        let epoch = PROFILER.peak_epoch.load(Ordering::Relaxed);
+        for (key, site) in PROFILER.allocation_sites.lock().iter() {
+            let mut counters = site_counters(site);
+            counters[8] += live_lifetimes.get(key).copied().unwrap_or(0);
+            if site.peak_epoch != epoch {
+                counters[4] = counters[2];
+                counters[5] = counters[3];
+            }
+            if self.sites.get(key) != Some(&counters) {
+                let mut site = site.clone();
+                site.settle_peak(epoch);
+                site.total_lifetime_us = counters[8];
+                sites.push(site);
+                self.sites.insert(key.clone(), counters);
+            }
+        }
+
+        let current_memory = PROFILER.current_memory.load(Ordering::Relaxed);
+        let samples = PROFILER.timeline.lock();
+        let unsent = samples.partition_point(|sample| Some(sample.time_us) <= self.timeline);
+        let mut timeline = samples[unsent..].to_vec();
+        drop(samples);
+        if let Some(last) = timeline.last() {
+            self.timeline = Some(last.time_us);
+        }
+        if finish {
+            timeline.push(MemorySample {
+                time_us: now_us,
+                current_memory,
+            });
+        }
+
+        let large_allocations =
+            PROFILER.large_allocations.lock()[self.large_allocations..].to_vec();
+        self.large_allocations += large_allocations.len();
+
+        // Threads are only ever added
+        let threads = PROFILER.threads.lock();
+        let new_threads = if threads.len() != self.threads {
+            self.threads = threads.len();
+            threads.clone()
+        } else {
+            HashMap::new()
+        };
+        drop(threads);
+
+        let benchmarks = PROFILER.benchmarks.lock();
+        let changed_benchmarks = (*benchmarks != self.benchmarks).then(|| {
+            self.benchmarks = benchmarks.clone();
+            benchmarks.clone()
+        });
+        drop(benchmarks);
+
+        ProfileDelta {
+            total_allocations: PROFILER.total_allocations.load(Ordering::Relaxed),
+            total_deallocations: PROFILER.total_deallocations.load(Ordering::Relaxed),
+            total_bytes_allocated: PROFILER.total_bytes_allocated.load(Ordering::Relaxed),
+            peak_memory: PROFILER.peak_memory.load(Ordering::Relaxed),
+            current_memory,
+            peak_time_us: PROFILER.peak_time_us.load(Ordering::Relaxed),
+            sites,
+            timeline,
+            large_allocations,
+            threads: new_threads,
+            benchmarks: changed_benchmarks,
+            partial: finish && REPORT_PARTIAL.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Connect a forked child whose parent was streaming to `cargo alloc-profile`.
+// Must be called with the reentrancy guard held.
+fn reconnect_after_fork() {
+    if RECONNECT.load(Ordering::Relaxed)
+        && RECONNECT.swap(false, Ordering::Relaxed)
+        && let Ok(address) = std::env::var("CARGO_ALLOC_PROFILE_SOCKET")
+    {
+        start_stream(&address);
+    }
+}
+
+// Every profiler lock is held while the process forks, so a child never
+// inherits a lock that another thread of its parent was holding, and the
+// child then starts a profile of its own
+#[cfg(unix)]
+fn register_fork_handlers() {
+    unsafe extern "C" {
+        fn pthread_atfork(
+            prepare: Option<extern "C" fn()>,
+            parent: Option<extern "C" fn()>,
+            child: Option<extern "C" fn()>,
+        ) -> std::ffi::c_int;
+    }
+
+    static REGISTERED: std::sync::Once = std::sync::Once::new();
+    REGISTERED.call_once(|| {
+        // SAFETY: the handlers are functions that live as long as the program
+        unsafe {
+            pthread_atfork(
+                Some(before_fork),
+                Some(after_fork_in_parent),
+                Some(after_fork_in_child),
+            );
+        }
+    });
+}
+
+// Locks are taken in the order the flush thread takes them
+#[cfg(unix)]
+extern "C" fn before_fork() {
+    let was_in_profiler = IN_PROFILER.with(|flag| flag.replace(true));
+    FORK_WAS_IN_PROFILER.with(|flag| flag.set(was_in_profiler));
+
+    std::mem::forget(STREAM.lock());
+    std::mem::forget(PROFILER.stack_config.write());
+    std::mem::forget(PROFILER.allocation_sites.lock());
+    std::mem::forget(PROFILER.live_allocations.lock());
+    std::mem::forget(PROFILER.timeline.lock());
+    std::mem::forget(PROFILER.large_allocations.lock());
+    std::mem::forget(PROFILER.threads.lock());
+    std::mem::forget(PROFILER.benchmarks.lock());
+}
+
+#[cfg(unix)]
+extern "C" fn after_fork_in_parent() {
+    // SAFETY: `before_fork` locked all of them and forgot the guards
+    unsafe { unlock_after_fork() };
+    IN_PROFILER.with(|flag| flag.set(FORK_WAS_IN_PROFILER.with(Cell::get)));
+}
+
+#[cfg(unix)]
+extern "C" fn after_fork_in_child() {
+    // SAFETY: `before_fork` locked all of them and forgot the guards
+    unsafe { unlock_after_fork() };
+    reset_after_fork();
+    IN_PROFILER.with(|flag| flag.set(FORK_WAS_IN_PROFILER.with(Cell::get)));
+}
+
+#[cfg(unix)]
+unsafe fn unlock_after_fork() {
+    // SAFETY: the caller guarantees every lock is held by a forgotten guard
+    unsafe {
+        PROFILER.benchmarks.force_unlock();
+        PROFILER.threads.force_unlock();
+        PROFILER.large_allocations.force_unlock();
+        PROFILER.timeline.force_unlock();
+        PROFILER.live_allocations.force_unlock();
+        PROFILER.allocation_sites.force_unlock();
+        PROFILER.stack_config.force_unlock_write();
+        STREAM.force_unlock();
+    }
+}
+
+// Start the profile of a forked child from scratch. What it inherited from
+// its parent is leaked rather than freed, as freeing it would touch every
+// page shared with the parent, often just before an `exec`. Must be called
+// with the reentrancy guard held.
+#[cfg(unix)]
+fn reset_after_fork() {
+    PROFILER.total_allocations.store(0, Ordering::Relaxed);
+    PROFILER.total_deallocations.store(0, Ordering::Relaxed);
+    PROFILER.total_bytes_allocated.store(0, Ordering::Relaxed);
+    PROFILER.peak_memory.store(0, Ordering::Relaxed);
+    PROFILER.current_memory.store(0, Ordering::Relaxed);
+    PROFILER.peak_time_us.store(0, Ordering::Relaxed);
+    PROFILER.last_sample_us.store(NO_SAMPLE, Ordering::Relaxed);
+    std::mem::forget(std::mem::take(&mut *PROFILER.allocation_sites.lock()));
+    std::mem::forget(std::mem::take(&mut *PROFILER.live_allocations.lock()));
+    std::mem::forget(std::mem::take(&mut *PROFILER.timeline.lock()));
+    std::mem::forget(std::mem::take(&mut *PROFILER.large_allocations.lock()));
+    std::mem::forget(std::mem::take(&mut *PROFILER.threads.lock()));
+    std::mem::forget(std::mem::take(&mut *PROFILER.benchmarks.lock()));
+    // The forking thread is the only one left, and registers again
+    THREAD_ID.with(|id| id.set(0));
+    REPORT_WRITTEN.store(false, Ordering::Relaxed);
+    REPORT_PARTIAL.store(false, Ordering::Relaxed);
+    restore_default_signals();
+
+    // Close the child's copy of the parent's connection; the child connects
+    // on its own when it next allocates
+    if let Some(stream) = STREAM.lock().take() {
+        let Stream {
+            writer,
+            sites,
+            benchmarks,
+            ..
+        } = stream;
+        drop(writer);
+        std::mem::forget((sites, benchmarks));
+        RECONNECT.store(true, Ordering::Relaxed);
+    }
+    STREAM_STARTED.store(false, Ordering::Relaxed);
+}
+
+// A copy of the sites in which every site has settled its counts at the
+// latest heap peak, see `AllocationSite::settle_peak`
+fn settled_sites(sites: &HashMap<String, AllocationSite>) -> HashMap<String, AllocationSite> {
+    let epoch = PROFILER.peak_epoch.load(Ordering::Relaxed);
+    sites
+        .iter()
+        .map(|(key, site)| {
+            let mut site = site.clone();
+            site.settle_peak(epoch);
+            (key.clone(), site)
+        })
+        .collect()
+}
+
+// Lifetimes of the allocations that are still live, up to `now_us`, per site
+fn live_lifetimes(now_us: u64) -> HashMap<String, u64> {
+    let mut lifetimes: HashMap<String, u64> = HashMap::new();
+    for live in PROFILER.live_allocations.lock().values() {
+        *lifetimes.entry(live.site_key.clone()).or_default() += now_us.saturating_sub(live.time_us);
+    }
+    lifetimes
+}
+
+#[cfg(unix)]
+fn parent_pid() -> u32 {
+    std::os::unix::process::parent_id()
+}
+
+#[cfg(not(unix))]
+fn parent_pid() -> u32 {
+    0
+}
+
+fn command_line() -> String {
+    std::env::args().collect::<Vec<_>>().join(" ")
+}
+
+// Sites with the same stack are kept apart by span
+fn site_key(frames: &[String], span: Option<&str>) -> String {
+    let stack = frames.join("\n");
+    match span {
+        Some(span) => format!("{}\n[{}]", stack, span),
+        None => stack,
+    }
+}
+
+// Path of the innermost span entered on this thread. Must be called with the
+// reentrancy guard held, since it allocates.
+fn current_span() -> Option<String> {
+    SPANS
+        .try_with(|spans| spans.try_borrow().ok()?.last().cloned())
+        .ok()
+        .flatten()
+}
+
+fn env_setting(name: &str) -> Option<u64> {
+    std::env::var(name).ok()?.trim().parse().ok()
+}
+
+fn env_list(name: &str) -> Option<Vec<String>> {
+    let value = std::env::var(name).ok()?;
+    Some(
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect(),
+    )
+}
+
+// Run `f` without recording the allocations it makes, so it can take
+// profiler locks that the recording path also takes
+fn without_profiling<R>(f: impl FnOnce() -> R) -> R {
+    let _guard = ReentrancyGuard::hold();
+    f()
+}
+
+// Sets the reentrancy flag of the current thread and restores it when
+// dropped, even when a panic unwinds through the profiler, which would
+// otherwise leave the thread unprofiled for good
+struct ReentrancyGuard {
+    was_in_profiler: bool,
+}
+
+impl ReentrancyGuard {
+    // The guard, unless the thread is already inside the profiler
+    fn enter() -> Option<ReentrancyGuard> {
+        let guard = Self::hold();
+        (!guard.was_in_profiler).then_some(guard)
+    }
+
+    fn hold() -> ReentrancyGuard {
+        ReentrancyGuard {
+            was_in_profiler: IN_PROFILER.with(|flag| flag.replace(true)),
+        }
+    }
+}
+
+impl Drop for ReentrancyGuard {
+    fn drop(&mut self) {
+        IN_PROFILER.with(|flag| flag.set(self.was_in_profiler));
+    }
+}
+
+fn elapsed_us() -> u64 {
+    PROFILER
+        .started
+        .get()
+        .map(|started| started.elapsed().as_micros() as u64)
+        .unwrap_or(0)
+}
+
+// Small stable id for the current thread, registering its name on first use.
+// Must be called with the reentrancy guard held, since it may allocate.
+fn current_thread_id() -> u64 {
+    THREAD_ID.with(|id| {
+        if id.get() == 0 {
+            let new_id = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+            let name = std::thread::current()
+                .name()
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("thread-{}", new_id));
+            PROFILER.threads.lock().insert(new_id, name);
+            id.set(new_id);
+        }
+        id.get()
+    })
+}
+
+fn extract_frames(backtrace: &Backtrace) -> Vec<String> {
+    let config = PROFILER.stack_config.read();
+    let mut frames = Vec::new();
+    let mut past_profiler = false;
+
+    for frame in backtrace.frames() {
+        for symbol in frame.symbols() {
+            if frames.len() >= config.max_depth {
+                return frames;
+            }
+
+            if let Some(name) = symbol.name() {
+                let name_str = name.to_string();
+                let path = symbol_path(&name_str);
+
+                // Everything up to the allocator belongs to the profiler
+                if INTERNAL_FRAMES.iter().any(|p| path_has_prefix(&path, p)) {
+                    past_profiler = true;
+                    continue;
+                }
+                if !past_profiler || config.skips(&path) {
+                    continue;
+                }
+
+                // Clean up the symbol name
+                let clean_name = clean_symbol_name(&name_str);
+
+                // Include file and line if available
+                let location =
+                    if let (Some(file), Some(line)) = (symbol.filename(), symbol.lineno()) {
+                        format!("{} ({}:{})", clean_name, file.display(), line)
+                    } else {
+                        clean_name
+                    };
+
+                frames.push(location);
+            }
+        }
+    }
+
+    frames
+}
+
+// Symbol name reduced to a plain path for pattern matching: no leading `<`
+// or `&` from trait impls, and no `[hash]` crate disambiguators from v0
+// mangling
+fn symbol_path(name: &str) -> String {
+    let name = name.trim_start_matches(['<', '&']);
+    let mut path = String::with_capacity(name.len());
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '[' && chars.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+            let hash: String = chars.clone().take_while(|c| *c != ']').collect();
+            if hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                chars.nth(hash.len());
+                continue;
+            }
+        }
+        path.push(c);
+    }
+    path
+}
+
+// Whether `path` is `prefix` itself or an item nested inside it
+fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix).is_some_and(|rest| {
+        rest.is_empty() || rest.starts_with("::") || rest.starts_with(['<', ' '])
+    })
+}
+
+fn clean_symbol_name(name: &str) -> String {
+    // Remove hash suffixes like ::h1a2b3c4d5e6f7g8
+    let name = if let Some(pos) = name.rfind("::h") {
+        if name[pos + 3..].chars().all(|c| c.is_ascii_hexdigit()) {
+            &name[..pos]
+        } else {
+            name
+        }
+    } else {
+        name
+    };
+
+    // Simplify generic parameters
+    let name = name.replace("<", "‹").replace(">", "›");
+
+    name.to_string()
+}
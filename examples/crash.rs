@@ -0,0 +1,37 @@
+//! Example that ends without writing its report: it panics, stops itself
+//! with SIGTERM or waits to be interrupted
+//!
+//! Run with: cargo alloc-profile run --example crash -- panic
+
+use cargo_alloc_profile::AllocationProfiler;
+
+fn build_index(words: usize) -> Vec<String> {
+    (0..words).map(|i| format!("word-{}", i)).collect()
+}
+
+fn main() {
+    AllocationProfiler::enable();
+
+    println!("Crash Example\n");
+
+    let index = build_index(100);
+    println!("Indexed {} words", index.len());
+
+    match std::env::args().nth(1).as_deref() {
+        #[cfg(unix)]
+        Some("terminate") => {
+            // SAFETY: sends SIGTERM to this process
+            unsafe {
+                libc::kill(libc::getpid(), libc::SIGTERM);
+            }
+            // The profiler ends the process once it has written the report
+            loop {
+                std::thread::park();
+            }
+        }
+        Some("wait") => loop {
+            std::thread::park();
+        },
+        _ => panic!("index is corrupt"),
+    }
+}
@@ -0,0 +1,54 @@
+//! Example that starts child processes, each profiled on its own
+//!
+//! Run with: cargo alloc-profile run --example processes
+
+use cargo_alloc_profile::AllocationProfiler;
+use std::process::Command;
+
+fn build_index(words: usize) -> Vec<String> {
+    (0..words).map(|i| format!("word-{}", i)).collect()
+}
+
+// A forked child starts with an empty profile of its own
+#[cfg(unix)]
+fn fork_child() {
+    // SAFETY: the child only allocates and exits, and the profiler resets its
+    // state after the fork
+    unsafe {
+        let pid = libc::fork();
+        if pid == 0 {
+            let index = build_index(30);
+            println!("Forked child indexed {} words", index.len());
+            AllocationProfiler::write_report();
+            libc::_exit(0);
+        }
+        libc::waitpid(pid, std::ptr::null_mut(), 0);
+    }
+}
+
+fn main() {
+    AllocationProfiler::enable();
+
+    if std::env::args().nth(1).as_deref() == Some("worker") {
+        let index = build_index(50);
+        println!("Worker indexed {} words", index.len());
+        AllocationProfiler::write_report();
+        return;
+    }
+
+    println!("Processes Example\n");
+
+    let index = build_index(100);
+    println!("Indexed {} words", index.len());
+
+    #[cfg(unix)]
+    fork_child();
+
+    let status = Command::new(std::env::current_exe().unwrap())
+        .arg("worker")
+        .status()
+        .unwrap();
+    println!("Worker exited with {}", status);
+
+    AllocationProfiler::write_report();
+}
@@ -4,6 +4,15 @@
 
 use cargo_alloc_profile::AllocationProfiler;
 
+// Grows a Vec one push at a time, reallocating as it fills up
+fn push_with_realloc(count: usize) -> Vec<usize> {
+    let mut items = Vec::new();
+    for i in 0..count {
+        items.push(i);
+    }
+    items
+}
+
 fn main() {
     // Enable profiling unconditionally (the cargo wrapper sets the env var before this runs)
     AllocationProfiler::enable();
@@ -11,10 +20,7 @@ fn main() {
     println!("Running allocation test...\n");
 
     // Test 1: Vec without capacity - will reallocate
-    let mut items = Vec::new();
-    for i in 0..100 {
-        items.push(i);
-    }
+    let items = push_with_realloc(100);
     println!("Created {} items without capacity", items.len());
 
     // Test 2: Vec with capacity - single allocation
@@ -0,0 +1,46 @@
+//! Example attributing allocations to `tracing` spans
+//!
+//! Run with: cargo alloc-profile --group-by span run --example tracing_spans
+
+use cargo_alloc_profile::AllocationProfiler;
+use cargo_alloc_profile::tracing::AllocationLayer;
+use tracing::info_span;
+use tracing_subscriber::prelude::*;
+
+fn parse(input: &str) -> Vec<String> {
+    let _span = info_span!("parse").entered();
+    input
+        .split(',')
+        .map(|field| field.trim().to_string())
+        .collect()
+}
+
+fn render(fields: &[String]) -> String {
+    let _span = info_span!("render").entered();
+    let mut output = String::new();
+    for field in fields {
+        output = output + "<td>" + field + "</td>";
+    }
+    output
+}
+
+fn handle(input: &str) -> String {
+    let _span = info_span!("request").entered();
+    let fields = parse(input);
+    render(&fields)
+}
+
+fn main() {
+    tracing_subscriber::registry().with(AllocationLayer).init();
+    AllocationProfiler::enable();
+
+    println!("Tracing Span Example\n");
+
+    for i in 0..20 {
+        let _page = handle(&format!("id {}, name {}, score {}", i, i * 2, i * 3));
+    }
+
+    println!("Each allocation above is reported under its request span");
+
+    AllocationProfiler::write_report();
+}
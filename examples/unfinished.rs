@@ -0,0 +1,25 @@
+//! Example that enables profiling but never writes its report, like a
+//! program that exits early or crashes
+//!
+//! Run with: cargo alloc-profile run --example unfinished
+
+use cargo_alloc_profile::AllocationProfiler;
+use std::time::Duration;
+
+fn build_index(words: usize) -> Vec<String> {
+    (0..words).map(|i| format!("word-{}", i)).collect()
+}
+
+fn main() {
+    AllocationProfiler::enable();
+
+    println!("Unfinished Example\n");
+
+    let index = build_index(100);
+    println!("Indexed {} words", index.len());
+
+    // Give the profiler time to send an update, then exit without
+    // `write_report`
+    std::thread::sleep(Duration::from_millis(500));
+    std::process::exit(0);
+}
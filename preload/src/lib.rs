@@ -0,0 +1,326 @@
+//! `LD_PRELOAD` interposer for `cargo alloc-profile exec`
+//!
+//! Replaces `malloc`, `calloc`, `realloc`, `free` and `posix_memalign` in the
+//! process it is loaded into, forwards each call to the next definition in
+//! link order (normally the C library) and records it with the same
+//! [`AllocationProfiler`] used by the Rust global allocator. Profiling starts
+//! when `CARGO_ALLOC_PROFILE=1` is set, and the report is written to
+//! `CARGO_ALLOC_PROFILE_OUTPUT` when the process exits.
+//!
+//! Interposing relies on `.init_array` and `dlsym(RTLD_NEXT)`, so the library
+//! is empty on targets that do not use ELF.
+
+#![cfg(all(unix, not(target_vendor = "apple")))]
+
+use backtrace::Backtrace;
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::{Cell, UnsafeCell};
+use std::ffi::{CStr, c_int, c_void};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// The recording core rather than the `cargo-alloc-profile` library, whose
+// global allocator would otherwise be installed in this library as well
+use cargo_alloc_profile_core::AllocationProfiler;
+
+type MallocFn = unsafe extern "C" fn(usize) -> *mut c_void;
+type CallocFn = unsafe extern "C" fn(usize, usize) -> *mut c_void;
+type ReallocFn = unsafe extern "C" fn(*mut c_void, usize) -> *mut c_void;
+type FreeFn = unsafe extern "C" fn(*mut c_void);
+type PosixMemalignFn = unsafe extern "C" fn(*mut *mut c_void, usize, usize) -> c_int;
+
+// Addresses of the real allocator functions, 0 until resolved
+static REAL_MALLOC: AtomicUsize = AtomicUsize::new(0);
+static REAL_CALLOC: AtomicUsize = AtomicUsize::new(0);
+static REAL_REALLOC: AtomicUsize = AtomicUsize::new(0);
+static REAL_FREE: AtomicUsize = AtomicUsize::new(0);
+static REAL_POSIX_MEMALIGN: AtomicUsize = AtomicUsize::new(0);
+
+static RESOLVED: AtomicBool = AtomicBool::new(false);
+static RESOLVING: AtomicBool = AtomicBool::new(false);
+
+// Whether allocations are currently recorded
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Allocations made while the real functions are being looked up (`dlsym`
+// itself may allocate) are served from this arena and never freed
+const ARENA_SIZE: usize = 64 * 1024;
+
+#[repr(C, align(16))]
+struct Arena(UnsafeCell<[u8; ARENA_SIZE]>);
+
+// SAFETY: every byte of the arena is handed out at most once
+unsafe impl Sync for Arena {}
+
+static ARENA: Arena = Arena(UnsafeCell::new([0; ARENA_SIZE]));
+static ARENA_USED: AtomicUsize = AtomicUsize::new(0);
+
+// Alignment guaranteed by the C allocator
+const MIN_ALIGN: usize = 16;
+
+// Reentrancy guard - capturing and recording a stack allocates too
+thread_local! {
+    static IN_HOOK: Cell<bool> = const { Cell::new(false) };
+}
+
+// Memory used by this library itself goes straight to the real allocator, so
+// it is never recorded
+struct RealAllocator;
+
+unsafe impl GlobalAlloc for RealAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.align() <= MIN_ALIGN && layout.align() <= layout.size() {
+            unsafe { real_malloc(layout.size()) as *mut u8 }
+        } else {
+            let mut ptr = std::ptr::null_mut();
+            let align = layout.align().max(size_of::<usize>());
+            match unsafe { real_posix_memalign(&mut ptr, align, layout.size()) } {
+                0 => ptr as *mut u8,
+                _ => std::ptr::null_mut(),
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        unsafe { real_free(ptr as *mut c_void) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: RealAllocator = RealAllocator;
+
+#[used]
+#[unsafe(link_section = ".init_array")]
+static INIT: extern "C" fn() = init;
+
+extern "C" fn init() {
+    if std::env::var_os("CARGO_ALLOC_PROFILE").is_some_and(|value| value == "1") {
+        AllocationProfiler::enable();
+        AllocationProfiler::write_report_at_exit();
+        ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// # Safety
+///
+/// Same contract as the C library `malloc`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn malloc(size: usize) -> *mut c_void {
+    let ptr = unsafe { real_malloc(size) };
+    record_allocation(ptr, size);
+    ptr
+}
+
+/// # Safety
+///
+/// Same contract as the C library `calloc`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn calloc(count: usize, size: usize) -> *mut c_void {
+    let ptr = unsafe { real_calloc(count, size) };
+    record_allocation(ptr, count.saturating_mul(size));
+    ptr
+}
+
+/// # Safety
+///
+/// Same contract as the C library `realloc`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: usize) -> *mut c_void {
+    // Detach first, so the address cannot be handed out again meanwhile
+    let detached = if ptr.is_null() || !ENABLED.load(Ordering::Relaxed) {
+        None
+    } else {
+        with_hook(|| AllocationProfiler::detach(ptr as usize)).flatten()
+    };
+
+    let new_ptr = unsafe { real_realloc(ptr, size) };
+
+    // A failed reallocation leaves the old block in place, except that a
+    // zero size frees it
+    if let Some(detached) = detached {
+        if new_ptr.is_null() && size != 0 {
+            with_hook(|| detached.restore());
+        } else {
+            with_hook(|| detached.release());
+        }
+    }
+    record_allocation(new_ptr, size);
+    new_ptr
+}
+
+/// # Safety
+///
+/// Same contract as the C library `free`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn free(ptr: *mut c_void) {
+    // Record first, so the address cannot be handed out again meanwhile
+    record_free(ptr);
+    unsafe { real_free(ptr) }
+}
+
+/// # Safety
+///
+/// Same contract as the C library `posix_memalign`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn posix_memalign(
+    memptr: *mut *mut c_void,
+    alignment: usize,
+    size: usize,
+) -> c_int {
+    let result = unsafe { real_posix_memalign(memptr, alignment, size) };
+    if result == 0 {
+        record_allocation(unsafe { *memptr }, size);
+    }
+    result
+}
+
+fn record_allocation(ptr: *mut c_void, size: usize) {
+    if ptr.is_null() || !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    with_hook(|| {
+        AllocationProfiler::record_allocation_at(ptr as usize, size, Backtrace::new_unresolved())
+    });
+}
+
+fn record_free(ptr: *mut c_void) {
+    if ptr.is_null() || !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    with_hook(|| AllocationProfiler::record_free(ptr as usize));
+}
+
+// Run `f` unless this thread is already inside a hook
+fn with_hook<R>(f: impl FnOnce() -> R) -> Option<R> {
+    let entered = IN_HOOK
+        .try_with(|flag| !flag.replace(true))
+        .unwrap_or(false);
+    if !entered {
+        return None;
+    }
+    let result = f();
+    let _ = IN_HOOK.try_with(|flag| flag.set(false));
+    Some(result)
+}
+
+// Look up the real allocator functions. Returns false while the lookup is in
+// progress, in which case callers fall back to the arena.
+fn resolve() -> bool {
+    if RESOLVED.load(Ordering::Acquire) {
+        return true;
+    }
+    if RESOLVING.swap(true, Ordering::AcqRel) {
+        return false;
+    }
+
+    let symbols: [(&AtomicUsize, &CStr); 5] = [
+        (&REAL_MALLOC, c"malloc"),
+        (&REAL_CALLOC, c"calloc"),
+        (&REAL_REALLOC, c"realloc"),
+        (&REAL_FREE, c"free"),
+        (&REAL_POSIX_MEMALIGN, c"posix_memalign"),
+    ];
+    for (slot, name) in symbols {
+        let address = unsafe { libc::dlsym(libc::RTLD_NEXT, name.as_ptr()) };
+        slot.store(address as usize, Ordering::Release);
+    }
+
+    RESOLVED.store(true, Ordering::Release);
+    true
+}
+
+// The real function stored in `slot`, if it has been resolved
+fn real(slot: &AtomicUsize) -> Option<usize> {
+    if !resolve() {
+        return None;
+    }
+    match slot.load(Ordering::Acquire) {
+        0 => None,
+        address => Some(address),
+    }
+}
+
+unsafe fn real_malloc(size: usize) -> *mut c_void {
+    match real(&REAL_MALLOC) {
+        Some(f) => unsafe { std::mem::transmute::<usize, MallocFn>(f)(size) },
+        None => arena_alloc(size, MIN_ALIGN),
+    }
+}
+
+unsafe fn real_calloc(count: usize, size: usize) -> *mut c_void {
+    match real(&REAL_CALLOC) {
+        Some(f) => unsafe { std::mem::transmute::<usize, CallocFn>(f)(count, size) },
+        // Arena memory starts out zeroed and is never reused
+        None => match count.checked_mul(size) {
+            Some(total) => arena_alloc(total, MIN_ALIGN),
+            None => std::ptr::null_mut(),
+        },
+    }
+}
+
+unsafe fn real_realloc(ptr: *mut c_void, size: usize) -> *mut c_void {
+    if in_arena(ptr) {
+        // Arena blocks do not record their size, so copy up to the end of
+        // the arena
+        let new_ptr = unsafe { real_malloc(size) };
+        if !new_ptr.is_null() {
+            let available = ARENA.0.get() as usize + ARENA_SIZE - ptr as usize;
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    ptr as *const u8,
+                    new_ptr as *mut u8,
+                    size.min(available),
+                )
+            };
+        }
+        return new_ptr;
+    }
+    match real(&REAL_REALLOC) {
+        Some(f) => unsafe { std::mem::transmute::<usize, ReallocFn>(f)(ptr, size) },
+        None => std::ptr::null_mut(),
+    }
+}
+
+unsafe fn real_free(ptr: *mut c_void) {
+    if ptr.is_null() || in_arena(ptr) {
+        return;
+    }
+    if let Some(f) = real(&REAL_FREE) {
+        unsafe { std::mem::transmute::<usize, FreeFn>(f)(ptr) }
+    }
+}
+
+unsafe fn real_posix_memalign(memptr: *mut *mut c_void, alignment: usize, size: usize) -> c_int {
+    match real(&REAL_POSIX_MEMALIGN) {
+        Some(f) => unsafe {
+            std::mem::transmute::<usize, PosixMemalignFn>(f)(memptr, alignment, size)
+        },
+        None => {
+            let ptr = arena_alloc(size, alignment);
+            if ptr.is_null() {
+                return libc::ENOMEM;
+            }
+            unsafe { *memptr = ptr };
+            0
+        }
+    }
+}
+
+fn arena_alloc(size: usize, align: usize) -> *mut c_void {
+    let base = ARENA.0.get() as usize;
+    let mut offset = ARENA_USED.load(Ordering::Relaxed);
+    loop {
+        let start = (base + offset).next_multiple_of(align) - base;
+        let Some(end) = start.checked_add(size).filter(|&end| end <= ARENA_SIZE) else {
+            return std::ptr::null_mut();
+        };
+        match ARENA_USED.compare_exchange_weak(offset, end, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return (base + start) as *mut c_void,
+            Err(current) => offset = current,
+        }
+    }
+}
+
+fn in_arena(ptr: *mut c_void) -> bool {
+    let base = ARENA.0.get() as usize;
+    (base..base + ARENA_SIZE).contains(&(ptr as usize))
+}
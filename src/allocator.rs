@@ -3,7 +3,7 @@ use std::alloc::{GlobalAlloc, Layout, System};
 use std::cell::Cell;
 
 thread_local! {
-    static IN_ALLOCATOR: Cell<bool> = Cell::new(false);
+    static IN_ALLOCATOR: Cell<bool> = const { Cell::new(false) };
 }
 
 pub struct ProfilingAllocator;
@@ -13,22 +13,12 @@ unsafe impl GlobalAlloc for ProfilingAllocator {
         // SAFETY: System is the standard allocator
         let ptr = unsafe { System.alloc(layout) };
 
-        if !ptr.is_null() {
-            // Check for reentrancy immediately
-            let should_profile = IN_ALLOCATOR.with(|flag| {
-                if flag.get() {
-                    false // Already in allocator, skip profiling
-                } else {
-                    flag.set(true);
-                    true
-                }
-            });
-
-            if should_profile {
-                let backtrace = backtrace::Backtrace::new_unresolved();
-                AllocationProfiler::record_allocation(layout.size(), backtrace);
-                IN_ALLOCATOR.with(|flag| flag.set(false));
-            }
+        // Check for reentrancy immediately
+        if !ptr.is_null()
+            && let Some(_guard) = AllocatorGuard::enter()
+        {
+            let backtrace = capture_stack();
+            AllocationProfiler::record_allocation_at(ptr as usize, layout.size(), backtrace);
         }
 
         ptr
@@ -38,38 +28,67 @@ unsafe impl GlobalAlloc for ProfilingAllocator {
         // Only record deallocations when not in a reentrant call
         let should_profile = IN_ALLOCATOR.with(|flag| !flag.get());
         if should_profile {
-            AllocationProfiler::record_deallocation(layout.size());
+            AllocationProfiler::record_deallocation_at(ptr as usize, layout.size());
         }
         // SAFETY: System is the standard allocator, ptr/layout come from alloc
         unsafe { System.dealloc(ptr, layout) };
     }
 
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // Detach first, so the address cannot be handed out again meanwhile
+        let detached =
+            AllocatorGuard::enter().and_then(|_guard| AllocationProfiler::detach(ptr as usize));
+
         // SAFETY: System is the standard allocator, ptr/layout come from alloc
         let new_ptr = unsafe { System.realloc(ptr, layout, new_size) };
 
-        if !new_ptr.is_null() {
-            // Check for reentrancy
-            let should_profile = IN_ALLOCATOR.with(|flag| {
-                if flag.get() {
-                    false
-                } else {
-                    flag.set(true);
-                    true
-                }
-            });
-
-            if should_profile {
-                // Record deallocation of old size and allocation of new size
-                AllocationProfiler::record_deallocation(layout.size());
-                let backtrace = backtrace::Backtrace::new_unresolved();
-                AllocationProfiler::record_allocation(new_size, backtrace);
-                IN_ALLOCATOR.with(|flag| flag.set(false));
+        // Check for reentrancy
+        if let Some(_guard) = AllocatorGuard::enter() {
+            // A failed reallocation leaves the old block in place
+            match detached {
+                Some(detached) if new_ptr.is_null() => detached.restore(),
+                Some(detached) => detached.release(),
+                None => {}
+            }
+            if !new_ptr.is_null() {
+                let backtrace = capture_stack();
+                AllocationProfiler::record_allocation_at(new_ptr as usize, new_size, backtrace);
             }
         }
 
         new_ptr
     }
 }
+// Sets the reentrancy flag of the current thread until dropped, so a panic
+// while recording cannot leave the thread unprofiled
+struct AllocatorGuard;
+
+impl AllocatorGuard {
+    // The guard, unless the thread is already in the allocator
+    fn enter() -> Option<AllocatorGuard> {
+        let already_in_allocator = IN_ALLOCATOR.with(|flag| flag.replace(true));
+        if already_in_allocator {
+            None
+        } else {
+            Some(AllocatorGuard)
+        }
+    }
+}
+
+impl Drop for AllocatorGuard {
+    fn drop(&mut self) {
+        IN_ALLOCATOR.with(|flag| flag.set(false));
+    }
+}
+
+// The stack of the current allocation, or an empty one when stack capture is off
+fn capture_stack() -> backtrace::Backtrace {
+    if AllocationProfiler::captures_stacks() {
+        backtrace::Backtrace::new_unresolved()
+    } else {
+        backtrace::Backtrace::from(Vec::new())
+    }
+}
+
 #[global_allocator]
 static GLOBAL: ProfilingAllocator = ProfilingAllocator;
@@ -0,0 +1,298 @@
+//! Collection of the profiles streamed by profiled programs.
+//!
+//! [`Collector::start`] listens on a Unix socket (a loopback TCP port where
+//! Unix sockets are not available) and passes its address to the program in
+//! `CARGO_ALLOC_PROFILE_SOCKET`. Every process that enables profiling
+//! connects, sends a `Start` message and then the changes to its profile as
+//! they happen, ending with a `Finish` message when it writes its report.
+//! Updates are folded into a snapshot per process as they arrive, so a
+//! process that crashes still leaves the data up to its last update.
+
+use cargo_alloc_profile::ProfileSnapshot;
+use cargo_alloc_profile::profiler::StreamMessage;
+use std::io::{BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// How long to wait in all for open connections once the program has exited,
+// in case a process it started in the background still holds one
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The profile of one process
+pub struct Process {
+    pub snapshot: ProfileSnapshot,
+    /// Whether the process finished its report; otherwise it exited or was
+    /// killed with the snapshot as of its last update
+    pub finished: bool,
+    closed: bool,
+}
+
+#[cfg(test)]
+impl Process {
+    /// A process that finished with `snapshot`
+    pub fn finished(snapshot: ProfileSnapshot) -> Process {
+        Process {
+            snapshot,
+            finished: true,
+            closed: true,
+        }
+    }
+}
+
+enum Event {
+    Connected(usize),
+    Message(usize, Box<StreamMessage>),
+    Closed(usize),
+}
+
+pub struct Collector {
+    receiver: Receiver<Event>,
+    stop: Arc<AtomicBool>,
+    acceptor: Option<JoinHandle<()>>,
+    socket: Option<PathBuf>,
+    processes: Vec<Process>,
+}
+
+impl Collector {
+    /// Listen for profiled processes and point `cmd` at the listener.
+    /// `interval_ms` overrides how often processes send updates.
+    pub fn start(cmd: &mut Command, interval_ms: Option<u64>) -> std::io::Result<Collector> {
+        let (listener, address, socket) = Listener::bind()?;
+        cmd.env("CARGO_ALLOC_PROFILE_SOCKET", address);
+        if let Some(interval) = interval_ms {
+            cmd.env("CARGO_ALLOC_PROFILE_INTERVAL_MS", interval.to_string());
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let acceptor = {
+            let stop = stop.clone();
+            std::thread::spawn(move || accept(listener, sender, &stop))
+        };
+
+        Ok(Collector {
+            receiver,
+            stop,
+            acceptor: Some(acceptor),
+            socket,
+            processes: Vec::new(),
+        })
+    }
+
+    /// Apply the messages received so far. Returns whether anything changed.
+    pub fn poll(&mut self) -> bool {
+        let mut changed = false;
+        while let Ok(event) = self.receiver.try_recv() {
+            self.apply(event);
+            changed = true;
+        }
+        changed
+    }
+
+    /// The processes seen so far, in the order they connected
+    pub fn processes(&self) -> &[Process] {
+        &self.processes
+    }
+
+    /// Once the program has exited, wait for every process to close its
+    /// connection and return all of them
+    pub fn finish(mut self) -> Vec<Process> {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(acceptor) = self.acceptor.take() {
+            let _ = acceptor.join();
+        }
+        // Every connection has been announced by now
+        self.poll();
+
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        while self.processes.iter().any(|process| !process.closed) {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.receiver.recv_timeout(timeout) {
+                Ok(event) => self.apply(event),
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        std::mem::take(&mut self.processes)
+    }
+
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::Connected(id) => {
+                debug_assert_eq!(id, self.processes.len());
+                self.processes.push(Process {
+                    snapshot: ProfileSnapshot::default(),
+                    finished: false,
+                    closed: false,
+                });
+            }
+            Event::Message(id, message) => {
+                let process = &mut self.processes[id];
+                match *message {
+                    StreamMessage::Start {
+                        pid,
+                        parent_pid,
+                        start_time_us,
+                        command,
+                    } => {
+                        process.snapshot.pid = pid;
+                        process.snapshot.parent_pid = parent_pid;
+                        process.snapshot.start_time_us = start_time_us;
+                        process.snapshot.command = command;
+                    }
+                    StreamMessage::Update(delta) => process.snapshot.apply(delta),
+                    StreamMessage::Finish(delta) => {
+                        process.snapshot.apply(delta);
+                        process.finished = true;
+                    }
+                }
+            }
+            Event::Closed(id) => self.processes[id].closed = true,
+        }
+    }
+}
+
+impl Drop for Collector {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(socket) = &self.socket {
+            let _ = std::fs::remove_file(socket);
+        }
+    }
+}
+
+/// Run the program to completion. SIGINT and SIGTERM only stop the program
+/// rather than this process as well, so the report the program writes as it
+/// stops can still be collected.
+pub fn run_program(cmd: &mut Command) -> std::io::Result<ExitStatus> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+
+        const SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+        // SAFETY: setting a disposition has no preconditions
+        let previous = SIGNALS.map(|signal| unsafe { libc::signal(signal, libc::SIG_IGN) });
+
+        // The program starts with the dispositions this process had before,
+        // except that handlers are reset to the default by exec anyway
+        // SAFETY: `signal` is async-signal-safe
+        unsafe {
+            cmd.pre_exec(move || {
+                for (signal, handler) in SIGNALS.into_iter().zip(previous) {
+                    if handler != libc::SIG_IGN {
+                        libc::signal(signal, libc::SIG_DFL);
+                    }
+                }
+                Ok(())
+            });
+        }
+
+        let status = cmd.status();
+        for (signal, handler) in SIGNALS.into_iter().zip(previous) {
+            // SAFETY: restores the disposition replaced above
+            unsafe { libc::signal(signal, handler) };
+        }
+        status
+    }
+
+    #[cfg(not(unix))]
+    cmd.status()
+}
+
+// Accept connections until stopped, reading each on its own thread
+fn accept(listener: Listener, sender: Sender<Event>, stop: &AtomicBool) {
+    let mut next_id = 0;
+    loop {
+        // Connections made before the stop are still accepted
+        let stopping = stop.load(Ordering::Relaxed);
+        match listener.accept() {
+            Ok(Some(stream)) => {
+                let id = next_id;
+                next_id += 1;
+                if sender.send(Event::Connected(id)).is_err() {
+                    return;
+                }
+                let sender = sender.clone();
+                std::thread::spawn(move || read(id, stream, sender));
+            }
+            Ok(None) | Err(_) if stopping => return,
+            Ok(None) | Err(_) => std::thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
+
+fn read(id: usize, stream: Box<dyn Read + Send>, sender: Sender<Event>) {
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        // A line cut short by a crash is dropped
+        let Ok(message) = serde_json::from_str::<StreamMessage>(&line) else {
+            continue;
+        };
+        if sender.send(Event::Message(id, Box::new(message))).is_err() {
+            return;
+        }
+    }
+    let _ = sender.send(Event::Closed(id));
+}
+
+// A non-blocking listener for profiled processes to connect to
+enum Listener {
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+    #[cfg(not(unix))]
+    Tcp(std::net::TcpListener),
+}
+
+impl Listener {
+    // The listener, the address to give to processes and the socket file to
+    // remove afterwards
+    #[cfg(unix)]
+    fn bind() -> std::io::Result<(Listener, String, Option<PathBuf>)> {
+        let path =
+            std::env::temp_dir().join(format!("cargo-alloc-profile-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        Ok((
+            Listener::Unix(listener),
+            path.display().to_string(),
+            Some(path),
+        ))
+    }
+
+    #[cfg(not(unix))]
+    fn bind() -> std::io::Result<(Listener, String, Option<PathBuf>)> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?.to_string();
+        Ok((Listener::Tcp(listener), address, None))
+    }
+
+    // The next pending connection, if any
+    fn accept(&self) -> std::io::Result<Option<Box<dyn Read + Send>>> {
+        let accepted = match self {
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.accept().and_then(|(stream, _)| {
+                stream.set_nonblocking(false)?;
+                Ok(Box::new(stream) as Box<dyn Read + Send>)
+            }),
+            #[cfg(not(unix))]
+            Listener::Tcp(listener) => listener.accept().and_then(|(stream, _)| {
+                stream.set_nonblocking(false)?;
+                Ok(Box::new(stream) as Box<dyn Read + Send>)
+            }),
+        };
+        match accepted {
+            Ok(stream) => Ok(Some(stream)),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Project defaults read from `.alloc-profile.toml` or the
+//! `[package.metadata.alloc-profile]` table of `Cargo.toml`.
+//!
+//! Keys are the long names of the command line flags, so `--sort-by size`
+//! becomes `sort-by = "size"`. Flags given on the command line always win.
+//!
+//! This module is part of the `cargo-alloc-profile` binary, not the library.
+
+use crate::{AttributionArg, GroupByArg, OutputFormatArg, SortByArg, SymbolsArg, ViewArg};
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::path::{Path, PathBuf};
+
+const CONFIG_FILE: &str = ".alloc-profile.toml";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    // Report options
+    pub verbose: Option<u8>,
+    pub filter: Option<String>,
+    pub output: Option<OutputFormatArg>,
+    pub min_count: Option<usize>,
+    pub threshold_bytes: Option<usize>,
+    pub sort_by: Option<SortByArg>,
+    pub limit: Option<usize>,
+    pub group_by: Option<GroupByArg>,
+    pub attribute: Option<AttributionArg>,
+    pub symbols: Option<SymbolsArg>,
+    pub view: Option<ViewArg>,
+    pub prune: Option<f64>,
+    #[serde(deserialize_with = "regex")]
+    pub focus: Option<Regex>,
+    #[serde(deserialize_with = "regex")]
+    pub ignore: Option<Regex>,
+    #[serde(deserialize_with = "regex")]
+    pub hide: Option<Regex>,
+
+    // Profiling settings
+    pub sample_interval_us: Option<u64>,
+    pub large_alloc_bytes: Option<usize>,
+    pub max_depth: Option<usize>,
+    pub skip_frames: Vec<String>,
+    pub keep_frames: Vec<String>,
+    pub no_inject: bool,
+}
+
+impl Config {
+    /// Load the configuration from `path`, or search the current directory
+    /// and its parents when no path is given
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        let table = match path {
+            Some(path) => read_table(path)?,
+            None => Self::discover()?,
+        };
+        Config::deserialize(toml::Value::Table(table))
+            .map_err(|e| format!("Invalid alloc-profile configuration: {}", e))
+    }
+
+    // The nearest Cargo.toml with an alloc-profile table, overlaid with the
+    // nearest .alloc-profile.toml
+    fn discover() -> Result<toml::Table, String> {
+        let cwd = std::env::current_dir()
+            .map_err(|e| format!("Failed to read current directory: {}", e))?;
+        let mut table = toml::Table::new();
+
+        for dir in cwd.ancestors() {
+            let manifest = dir.join("Cargo.toml");
+            if !manifest.is_file() {
+                continue;
+            }
+            let manifest = read_table(&manifest)?;
+            let metadata = ["package", "workspace"].into_iter().find_map(|section| {
+                manifest
+                    .get(section)?
+                    .get("metadata")?
+                    .get("alloc-profile")?
+                    .as_table()
+                    .cloned()
+            });
+            if let Some(metadata) = metadata {
+                table = metadata;
+                break;
+            }
+        }
+
+        if let Some(file) = find_upwards(&cwd, CONFIG_FILE) {
+            table.extend(read_table(&file)?);
+        }
+
+        Ok(table)
+    }
+}
+
+fn find_upwards(start: &Path, name: &str) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+fn read_table(path: &Path) -> Result<toml::Table, String> {
+    let data = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    data.parse()
+        .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+}
+
+fn regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Regex>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|pattern| Regex::new(&pattern).map_err(serde::de::Error::custom))
+        .transpose()
+}
@@ -0,0 +1,120 @@
+//! Per-benchmark allocation counts for Criterion benchmarks.
+//!
+//! [`BenchAllocations::bench_allocations`] is `bench_function` with the
+//! allocations and bytes of every iteration counted against the benchmark.
+//! Under `cargo alloc-profile bench` the counts end up in the profiling report
+//! as a per-benchmark table; under a plain `cargo bench` they are printed
+//! after each benchmark, with stack capture turned off so timings stay close
+//! to those of an unprofiled run.
+//!
+//! ```no_run
+//! use cargo_alloc_profile::criterion::BenchAllocations;
+//! use criterion::{Criterion, criterion_group, criterion_main};
+//!
+//! fn bench(c: &mut Criterion) {
+//!     c.bench_allocations("collect", || (0..100).collect::<Vec<u32>>());
+//! }
+//!
+//! criterion_group!(benches, bench);
+//! criterion_main!(benches);
+//! ```
+
+use crate::profiler::AllocationProfiler;
+use ::criterion::measurement::WallTime;
+use ::criterion::{Bencher, BenchmarkGroup, Criterion};
+use std::hint::black_box;
+use std::sync::Once;
+use std::time::Instant;
+
+static START: Once = Once::new();
+
+/// Criterion benchmarks that also count allocations per iteration
+pub trait BenchAllocations {
+    /// Benchmark `routine` like `bench_function`, also recording the
+    /// allocations and bytes of each iteration under `id`
+    fn bench_allocations<O, R>(&mut self, id: &str, routine: R) -> &mut Self
+    where
+        R: FnMut() -> O;
+}
+
+impl BenchAllocations for Criterion<WallTime> {
+    fn bench_allocations<O, R>(&mut self, id: &str, mut routine: R) -> &mut Self
+    where
+        R: FnMut() -> O,
+    {
+        self.bench_function(id, |b| iter(b, id, &mut routine));
+        print_counts(id);
+        self
+    }
+}
+
+/// Counts are recorded under `id` alone, without the group name
+impl BenchAllocations for BenchmarkGroup<'_, WallTime> {
+    fn bench_allocations<O, R>(&mut self, id: &str, mut routine: R) -> &mut Self
+    where
+        R: FnMut() -> O,
+    {
+        self.bench_function(id, |b| iter(b, id, &mut routine));
+        print_counts(id);
+        self
+    }
+}
+
+/// Time `routine` like `Bencher::iter`, recording the allocations and bytes
+/// of each iteration under `id`
+pub fn iter<O, R>(b: &mut Bencher<'_, WallTime>, id: &str, mut routine: R)
+where
+    R: FnMut() -> O,
+{
+    start();
+    b.iter_custom(|iterations| {
+        let (allocations, bytes) = AllocationProfiler::allocation_counts();
+        let started = Instant::now();
+        for _ in 0..iterations {
+            black_box(routine());
+        }
+        let elapsed = started.elapsed();
+        let (end_allocations, end_bytes) = AllocationProfiler::allocation_counts();
+
+        AllocationProfiler::record_benchmark(
+            id,
+            iterations,
+            end_allocations.saturating_sub(allocations) as u64,
+            end_bytes.saturating_sub(bytes) as u64,
+        );
+        elapsed
+    });
+}
+
+// Enable profiling on first use. When run by `cargo alloc-profile` the report
+// is written on exit; otherwise allocations are only counted.
+fn start() {
+    START.call_once(|| {
+        if AllocationProfiler::is_enabled() {
+            return;
+        }
+        if std::env::var_os("CARGO_ALLOC_PROFILE").is_some_and(|value| value == "1") {
+            AllocationProfiler::enable();
+            AllocationProfiler::write_report_at_exit();
+        } else {
+            AllocationProfiler::set_capture_stacks(false);
+            AllocationProfiler::enable();
+        }
+    });
+}
+
+// Print the per-iteration counts of `id` under Criterion's own output
+fn print_counts(id: &str) {
+    let benchmarks = AllocationProfiler::benchmarks();
+    if let Some(benchmark) = benchmarks.iter().find(|benchmark| benchmark.id == id)
+        && benchmark.iterations > 0
+    {
+        let iterations = benchmark.iterations as f64;
+        println!(
+            "{:<24} allocs: {:.2}/iter, bytes: {:.1}/iter\n",
+            "",
+            benchmark.allocations as f64 / iterations,
+            benchmark.bytes as f64 / iterations
+        );
+    }
+}
@@ -0,0 +1,380 @@
+//! Zero-setup profiling of `run`, `test` and `bench` targets.
+//!
+//! A small shim crate that links `cargo-alloc-profile` (and with it the
+//! profiling allocator) is generated and built once per target directory.
+//! The project is then built into a separate target directory with this
+//! executable as `RUSTC_WORKSPACE_WRAPPER`, which adds
+//! `extern crate cargo_alloc_profile_shim` to every binary, test and bench
+//! crate of the workspace. The shim enables profiling on startup and writes
+//! the report on exit, so nothing has to change in the profiled project.
+
+use crate::package;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+
+// Set in the environment of the wrapped cargo build; its presence makes this
+// executable act as the rustc wrapper
+const SHIM_ENV: &str = "CARGO_ALLOC_PROFILE_SHIM";
+const SHIM_DEPS_ENV: &str = "CARGO_ALLOC_PROFILE_SHIM_DEPS";
+// Set in the environment of the shim build to the user's own rustc wrapper,
+// possibly empty; its presence makes this executable embed bitcode instead
+const BITCODE_ENV: &str = "CARGO_ALLOC_PROFILE_BITCODE";
+
+const SHIM_CRATE: &str = "cargo_alloc_profile_shim";
+
+// Part of the error rustc gives when a crate links two global allocators
+const ALLOCATOR_CONFLICT: &str = "conflicts with global allocator";
+
+const SHIM_SOURCE: &str = r#"//! Generated by cargo-alloc-profile. Do not edit.
+
+extern crate cargo_alloc_profile;
+
+#[used]
+#[cfg_attr(
+    any(target_os = "linux", target_os = "android", target_os = "freebsd"),
+    unsafe(link_section = ".init_array")
+)]
+#[cfg_attr(target_vendor = "apple", unsafe(link_section = "__DATA,__mod_init_func"))]
+#[cfg_attr(windows, unsafe(link_section = ".CRT$XCU"))]
+static START: extern "C" fn() = start;
+
+extern "C" fn start() {
+    if std::env::var_os("CARGO_ALLOC_PROFILE").is_some_and(|value| value == "1") {
+        cargo_alloc_profile::AllocationProfiler::enable();
+        cargo_alloc_profile::AllocationProfiler::write_report_at_exit();
+    }
+}
+"#;
+
+/// Build the shim and set up `cmd` (a `cargo run`, `test` or `bench`
+/// invocation) to build into a separate target directory with the shim
+/// linked into every workspace binary
+pub fn prepare(cmd: &mut Command) -> Result<(), Box<dyn Error>> {
+    let target_dir = project_target_dir()?.join("alloc-profile");
+    let (shim, deps) = build_shim(&target_dir, build_target(cmd).as_deref())?;
+
+    cmd.env("CARGO_TARGET_DIR", &target_dir);
+    cmd.env("RUSTC_WORKSPACE_WRAPPER", std::env::current_exe()?);
+    cmd.env(SHIM_ENV, shim);
+    cmd.env(SHIM_DEPS_ENV, deps);
+    Ok(())
+}
+
+/// When running as the rustc wrapper set up by [`prepare`], compile the crate
+/// and return rustc's exit code
+pub fn run_as_rustc_wrapper() -> Option<i32> {
+    let bitcode_wrapper = std::env::var_os(BITCODE_ENV);
+    let shim = std::env::var_os(SHIM_ENV);
+    if bitcode_wrapper.is_none() && shim.is_none() {
+        return None;
+    }
+    let mut args = std::env::args_os().skip(1);
+    let rustc = args.next()?;
+    // Invoked as `cargo alloc-profile` from a profiled program, not by cargo
+    if rustc == "alloc-profile" {
+        return None;
+    }
+    if let Some(wrapper) = bitcode_wrapper {
+        let args: Vec<OsString> = args.collect();
+        return Some(exit_code(
+            compile_with_bitcode(&wrapper, &rustc, &args),
+            &rustc,
+        ));
+    }
+    let shim = shim?;
+    let mut args: Vec<String> = args.map(|arg| arg.to_string_lossy().into_owned()).collect();
+    let original = args.clone();
+
+    let injected = injection_root(&args).and_then(|index| {
+        let root = PathBuf::from(&args[index]);
+        let generated = generated_root(&root, &args);
+        let source = std::fs::read_to_string(&root).ok()?;
+        std::fs::write(
+            &generated,
+            format!(
+                "{}\n// Added by cargo-alloc-profile\nextern crate {} as _;\n",
+                source, SHIM_CRATE
+            ),
+        )
+        .ok()?;
+
+        let deps = std::env::var_os(SHIM_DEPS_ENV).unwrap_or_default();
+        args[index] = generated.display().to_string();
+        args.push("--extern".to_string());
+        args.push(format!("{}={}", SHIM_CRATE, Path::new(&shim).display()));
+        for dir in std::env::split_paths(&deps) {
+            args.push("-L".to_string());
+            args.push(format!("dependency={}", dir.display()));
+        }
+        // Report locations against the original file
+        args.push(format!(
+            "--remap-path-prefix={}={}",
+            generated.display(),
+            root.display()
+        ));
+        Some((root, generated))
+    });
+
+    let status = match injected {
+        Some(_) => compile_injected(&rustc, &args, &original),
+        None => Command::new(&rustc).args(&args).status(),
+    };
+
+    if let Some((root, generated)) = injected {
+        let _ = std::fs::remove_file(&generated);
+        fix_dep_info(&args, &root, &generated, Path::new(&shim));
+    }
+
+    Some(exit_code(status, &rustc))
+}
+
+fn exit_code(status: std::io::Result<ExitStatus>, rustc: &OsStr) -> i32 {
+    match status {
+        Ok(status) => status.code().unwrap_or(1),
+        Err(e) => {
+            eprintln!("error: failed to run {}: {}", rustc.to_string_lossy(), e);
+            1
+        }
+    }
+}
+
+// Compile a crate of the shim build with bitcode kept in the rlib, so
+// projects built with LTO can link it. Added here rather than to `RUSTFLAGS`,
+// which would replace the rustflags of the user's cargo config.
+fn compile_with_bitcode(
+    wrapper: &OsStr,
+    rustc: &OsStr,
+    args: &[OsString],
+) -> std::io::Result<ExitStatus> {
+    let mut cmd = if wrapper.is_empty() {
+        Command::new(rustc)
+    } else {
+        let mut cmd = Command::new(wrapper);
+        cmd.arg(rustc);
+        cmd
+    };
+    cmd.args(args);
+    // Not when cargo only queries rustc for its version
+    if args.iter().any(|arg| arg == "--crate-name") {
+        cmd.args(["-C", "embed-bitcode=yes"]);
+    }
+    cmd.status()
+}
+
+// Compile a crate with the shim linked in. A crate that already links another
+// global allocator, itself or through a dependency, cannot link the
+// profiler's, so it is compiled again as it is, unprofiled.
+fn compile_injected(
+    rustc: &OsStr,
+    args: &[String],
+    original: &[String],
+) -> std::io::Result<ExitStatus> {
+    let output = Command::new(rustc)
+        .args(args)
+        .stderr(Stdio::piped())
+        .spawn()?
+        .wait_with_output()?;
+    if !output.status.success()
+        && String::from_utf8_lossy(&output.stderr).contains(ALLOCATOR_CONFLICT)
+    {
+        let name = original
+            .windows(2)
+            .find(|pair| pair[0] == "--crate-name")
+            .map_or("the crate", |pair| pair[1].as_str());
+        eprintln!(
+            "warning: {} sets its own global allocator, so it is built without the profiler",
+            name
+        );
+        return Command::new(rustc).args(original).status();
+    }
+
+    std::io::stderr().write_all(&output.stderr)?;
+    Ok(output.status)
+}
+
+// Index of the crate root argument if this compiles a binary, test or bench
+// that does not already link the profiler itself
+fn injection_root(args: &[String]) -> Option<usize> {
+    let value = |flag: &str| {
+        args.windows(2)
+            .filter(|pair| pair[0] == flag)
+            .map(|pair| pair[1].as_str())
+            .collect::<Vec<_>>()
+    };
+
+    let is_bin = value("--crate-type").contains(&"bin");
+    let is_test = args.iter().any(|arg| arg == "--test");
+    let is_build_script = value("--crate-name")
+        .iter()
+        .any(|name| name.starts_with("build_script_"));
+    let links_profiler = value("--extern")
+        .iter()
+        .any(|dep| dep.starts_with("cargo_alloc_profile="));
+    if !(is_bin || is_test) || is_build_script || links_profiler {
+        return None;
+    }
+
+    args.iter()
+        .position(|arg| !arg.starts_with('-') && arg.ends_with(".rs") && Path::new(arg).is_file())
+}
+
+// The rewritten crate root sits next to the original, so `mod` declarations
+// and `include_str!` resolve exactly as they would for the original. Cargo
+// compiles some roots as several crates at once, such as `src/main.rs` as the
+// binary and as its unit tests, so every compilation gets its own file.
+fn generated_root(root: &Path, args: &[String]) -> PathBuf {
+    let stem = root
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let crate_name = args
+        .windows(2)
+        .find(|pair| pair[0] == "--crate-name")
+        .map_or("", |pair| pair[1].as_str());
+    root.with_file_name(format!(
+        ".{}.{}{}-{}.alloc-profile.rs",
+        stem,
+        crate_name,
+        extra_filename(args),
+        std::process::id()
+    ))
+}
+
+// The suffix cargo gives the outputs of a crate to tell its builds apart
+fn extra_filename(args: &[String]) -> &str {
+    args.iter()
+        .find_map(|arg| arg.strip_prefix("extra-filename="))
+        .unwrap_or("")
+}
+
+// Point the dep-info file at the original crate root, so cargo does not
+// rebuild the crate on every run because the generated root is gone, and add
+// the shim, so the crate is rebuilt when the shim changes
+fn fix_dep_info(args: &[String], root: &Path, generated: &Path, shim: &Path) {
+    let value = |flag: &str| {
+        args.windows(2)
+            .find(|pair| pair[0] == flag)
+            .map(|pair| pair[1].clone())
+    };
+    let (Some(out_dir), Some(crate_name)) = (value("--out-dir"), value("--crate-name")) else {
+        return;
+    };
+    let dep_info = Path::new(&out_dir).join(format!("{}{}.d", crate_name, extra_filename(args)));
+    let Ok(contents) = std::fs::read_to_string(&dep_info) else {
+        return;
+    };
+    let contents = contents.replace(
+        &generated.display().to_string(),
+        &root.display().to_string(),
+    );
+
+    // Cargo only reads the dependencies of the first rule
+    let mut fixed = String::new();
+    let mut added = false;
+    for line in contents.lines() {
+        fixed.push_str(line);
+        if !added && !line.starts_with('#') && line.contains(": ") {
+            fixed.push(' ');
+            fixed.push_str(&shim.display().to_string());
+            added = true;
+        }
+        fixed.push('\n');
+    }
+    let _ = std::fs::write(&dep_info, fixed);
+}
+
+// The target directory of the project in the current directory
+fn project_target_dir() -> Result<PathBuf, Box<dyn Error>> {
+    let output = Command::new("cargo")
+        .args(["metadata", "--format-version", "1", "--no-deps"])
+        .output()?;
+    if !output.status.success() {
+        return Err("Failed to read project metadata".into());
+    }
+    let metadata: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    metadata["target_directory"]
+        .as_str()
+        .map(PathBuf::from)
+        .ok_or_else(|| "Project metadata has no target directory".into())
+}
+
+// The `--target` of the cargo build, so the shim is built for the same
+// target. Arguments after `--` are for the program.
+fn build_target(cmd: &Command) -> Option<String> {
+    let args: Vec<_> = cmd
+        .get_args()
+        .map(|arg| arg.to_string_lossy())
+        .take_while(|arg| arg != "--")
+        .collect();
+    args.iter().enumerate().find_map(|(i, arg)| {
+        if arg == "--target" {
+            args.get(i + 1).map(|target| target.to_string())
+        } else {
+            arg.strip_prefix("--target=").map(str::to_string)
+        }
+    })
+}
+
+// Generate and build the shim crate, returning the path of its rlib and the
+// directories of its dependencies. The build runs in the current directory, so it picks up
+// the project's cargo config, including its target and rustflags.
+fn build_shim(
+    target_dir: &Path,
+    target: Option<&str>,
+) -> Result<(PathBuf, OsString), Box<dyn Error>> {
+    let dir = target_dir.join("shim");
+    package::write(
+        &dir,
+        "cargo-alloc-profile-shim",
+        SHIM_SOURCE,
+        "cargo-alloc-profile",
+        "",
+    )?;
+
+    let mut build = Command::new("cargo");
+    build
+        .env("RUSTC_WRAPPER", std::env::current_exe()?)
+        .env(
+            BITCODE_ENV,
+            std::env::var_os("RUSTC_WRAPPER").unwrap_or_default(),
+        )
+        .arg("build")
+        .arg("--quiet")
+        .arg("--message-format=json-render-diagnostics")
+        .arg("--manifest-path")
+        .arg(dir.join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(dir.join("target"));
+    if let Some(target) = target {
+        build.arg("--target").arg(target);
+    }
+    let output = build.stderr(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err("Failed to build the profiler shim".into());
+    }
+
+    // Where the outputs go depends on the target, and proc macros are built
+    // for the host, so take the locations from cargo
+    let artifacts: Vec<PathBuf> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|message| message["reason"] == "compiler-artifact")
+        .flat_map(|message| {
+            let filenames = message["filenames"].as_array().cloned();
+            filenames.into_iter().flatten()
+        })
+        .filter_map(|file| file.as_str().map(PathBuf::from))
+        .collect();
+    let rlib = artifacts
+        .iter()
+        .find(|file| file.file_name() == Some(OsStr::new(&format!("lib{}.rlib", SHIM_CRATE))))
+        .cloned()
+        .ok_or("The profiler shim build produced no library")?;
+    let mut dirs: Vec<&Path> = artifacts.iter().filter_map(|file| file.parent()).collect();
+    dirs.sort();
+    dirs.dedup();
+    Ok((rlib, std::env::join_paths(dirs)?))
+}
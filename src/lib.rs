@@ -1,9 +1,15 @@
 pub mod allocator;
+#[cfg(feature = "criterion")]
+pub mod criterion;
 pub mod profiler;
 pub mod reporter;
+#[cfg(feature = "tracing")]
+pub mod tracing;
 
 pub use allocator::ProfilingAllocator;
-pub use profiler::{AllocationProfiler, AllocationSite, ProfileSnapshot};
+pub use profiler::{
+    AllocationProfiler, AllocationSite, BenchmarkAllocations, ProfileSnapshot, StackConfig,
+};
 pub use reporter::Reporter;
 
 // Re-export for convenience
@@ -1,7 +1,22 @@
-use cargo_alloc_profile::reporter::{GroupBy, OutputFormat, ReportOptions, SortBy};
+use cargo_alloc_profile::reporter::{
+    Attribution, GroupBy, OutputFormat, ReportOptions, SortBy, SymbolStyle, View,
+};
 use clap::{Parser, Subcommand, ValueEnum};
+use collect::Collector;
+use config::Config;
+use regex::Regex;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
 use std::process;
 
+// Parts of the command line tool that are not in the library
+mod collect;
+mod config;
+mod inject;
+mod package;
+mod preload;
+mod watch;
+
 #[derive(Parser)]
 #[command(name = "cargo")]
 #[command(bin_name = "cargo")]
@@ -9,10 +24,25 @@ enum CargoCli {
     AllocProfile(AllocProfileArgs),
 }
 
-#[derive(Clone, ValueEnum)]
+#[derive(Debug, Clone, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 enum OutputFormatArg {
     Text,
     Json,
+    /// Gzipped pprof heap profile (profile.proto)
+    Pprof,
+    /// speedscope.app JSON profile
+    Speedscope,
+    /// Chrome Trace Event JSON timeline (Perfetto UI, chrome://tracing)
+    ChromeTrace,
+    /// Valgrind Massif massif.out snapshots (ms_print, massif-visualizer)
+    Massif,
+    /// DHAT JSON for Valgrind's dh_view.html
+    Dhat,
+    /// Markdown for pull request comments
+    Markdown,
+    /// SARIF 2.1.0 log for code scanning viewers
+    Sarif,
 }
 
 impl From<OutputFormatArg> for OutputFormat {
@@ -20,11 +50,19 @@ impl From<OutputFormatArg> for OutputFormat {
         match arg {
             OutputFormatArg::Text => OutputFormat::Text,
             OutputFormatArg::Json => OutputFormat::Json,
+            OutputFormatArg::Pprof => OutputFormat::Pprof,
+            OutputFormatArg::Speedscope => OutputFormat::Speedscope,
+            OutputFormatArg::ChromeTrace => OutputFormat::ChromeTrace,
+            OutputFormatArg::Massif => OutputFormat::Massif,
+            OutputFormatArg::Dhat => OutputFormat::Dhat,
+            OutputFormatArg::Markdown => OutputFormat::Markdown,
+            OutputFormatArg::Sarif => OutputFormat::Sarif,
         }
     }
 }
 
-#[derive(Clone, ValueEnum)]
+#[derive(Debug, Clone, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 enum SortByArg {
     Count,
     Size,
@@ -41,11 +79,19 @@ impl From<SortByArg> for SortBy {
     }
 }
 
-#[derive(Clone, ValueEnum)]
+#[derive(Debug, Clone, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 enum GroupByArg {
     Function,
     Module,
     File,
+    /// Dependency crate, resolved with `cargo metadata`
+    Crate,
+    /// Allocated container or smart pointer type, such as `Vec<Foo>`. Implies
+    /// `--keep-frames alloc`
+    Type,
+    /// Active tracing span, recorded by the `tracing` feature's layer
+    Span,
 }
 
 impl From<GroupByArg> for GroupBy {
@@ -54,6 +100,69 @@ impl From<GroupByArg> for GroupBy {
             GroupByArg::Function => GroupBy::Function,
             GroupByArg::Module => GroupBy::Module,
             GroupByArg::File => GroupBy::File,
+            GroupByArg::Crate => GroupBy::Crate,
+            GroupByArg::Type => GroupBy::Type,
+            GroupByArg::Span => GroupBy::Span,
+        }
+    }
+}
+
+#[derive(Debug, Clone, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SymbolsArg {
+    /// Symbol names as recorded
+    Full,
+    /// Standard library paths shortened, generics and hashes removed
+    Short,
+    /// Generic arguments, crate hashes and closure numbers removed
+    NoGenerics,
+}
+
+impl From<SymbolsArg> for SymbolStyle {
+    fn from(arg: SymbolsArg) -> Self {
+        match arg {
+            SymbolsArg::Full => SymbolStyle::Full,
+            SymbolsArg::Short => SymbolStyle::Short,
+            SymbolsArg::NoGenerics => SymbolStyle::NoGenerics,
+        }
+    }
+}
+
+#[derive(Debug, Clone, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum AttributionArg {
+    /// The frame that made the allocation
+    Allocation,
+    /// The first frame in the current workspace
+    Workspace,
+}
+
+impl From<AttributionArg> for Attribution {
+    fn from(arg: AttributionArg) -> Self {
+        match arg {
+            AttributionArg::Allocation => Attribution::Allocation,
+            AttributionArg::Workspace => Attribution::Workspace,
+        }
+    }
+}
+
+#[derive(Debug, Clone, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ViewArg {
+    /// One line per allocation site
+    Flat,
+    /// Call tree from callers down to allocating functions
+    Tree,
+    /// Call tree from allocating functions up to their callers
+    Inverted,
+}
+
+impl From<ViewArg> for View {
+    fn from(arg: ViewArg) -> Self {
+        match arg {
+            ViewArg::Flat => View::Flat,
+            ViewArg::Tree => View::Tree,
+            ViewArg::Inverted => View::Inverted,
         }
     }
 }
@@ -72,9 +181,9 @@ struct AllocProfileArgs {
     #[arg(short, long, global = true)]
     filter: Option<String>,
 
-    /// Output format
-    #[arg(short, long, value_enum, default_value = "text", global = true)]
-    output: OutputFormatArg,
+    /// Output format [default: text]
+    #[arg(short, long, value_enum, global = true)]
+    output: Option<OutputFormatArg>,
 
     /// Only show allocations that occurred at least this many times
     #[arg(long, global = true)]
@@ -84,9 +193,9 @@ struct AllocProfileArgs {
     #[arg(long, global = true)]
     threshold_bytes: Option<usize>,
 
-    /// Sort results by count, size, or name
-    #[arg(long, value_enum, default_value = "count", global = true)]
-    sort_by: SortByArg,
+    /// Sort results by count, size, or name [default: count]
+    #[arg(long, value_enum, global = true)]
+    sort_by: Option<SortByArg>,
 
     /// Show only the top N allocation sites
     #[arg(long, global = true)]
@@ -100,9 +209,128 @@ struct AllocProfileArgs {
     #[arg(long, global = true)]
     compare: Option<String>,
 
-    /// Aggregate allocations by function, module, or file
-    #[arg(long, value_enum, default_value = "function", global = true)]
-    group_by: GroupByArg,
+    /// Aggregate allocations by function, module, file, crate, or type [default: function]
+    #[arg(long, value_enum, global = true)]
+    group_by: Option<GroupByArg>,
+
+    /// Report each stack under the allocating frame or the first frame in
+    /// the workspace [default: allocation]
+    #[arg(long, value_enum, global = true)]
+    attribute: Option<AttributionArg>,
+
+    /// How to spell symbol names in reports [default: full]
+    #[arg(long, value_enum, global = true)]
+    symbols: Option<SymbolsArg>,
+
+    /// Show allocation sites as a flat list or as a merged call tree [default: flat]
+    #[arg(long, value_enum, global = true)]
+    view: Option<ViewArg>,
+
+    /// Hide call tree nodes below this percentage of the total [default: 1]
+    #[arg(long, value_name = "PERCENT", global = true)]
+    prune: Option<f64>,
+
+    /// Keep only stacks with a frame matching this regex, rooted at that frame
+    #[arg(long, value_name = "REGEX", global = true)]
+    focus: Option<Regex>,
+
+    /// Drop stacks with a frame matching this regex
+    #[arg(long, value_name = "REGEX", global = true)]
+    ignore: Option<Regex>,
+
+    /// Remove frames matching this regex from every stack
+    #[arg(long, value_name = "REGEX", global = true)]
+    hide: Option<Regex>,
+
+    /// Write the report to this file instead of stdout
+    #[arg(long, global = true)]
+    output_file: Option<String>,
+
+    /// Minimum time between heap usage samples on the timeline, in microseconds
+    #[arg(long, global = true)]
+    sample_interval_us: Option<u64>,
+
+    /// Record allocations of at least this many bytes as timeline events
+    #[arg(long, global = true)]
+    large_alloc_bytes: Option<usize>,
+
+    /// Most frames to record per allocation stack (default: 32)
+    #[arg(long, global = true)]
+    max_depth: Option<usize>,
+
+    /// Leave frames under this symbol path out of stacks (repeatable)
+    #[arg(long, value_name = "PATH", global = true)]
+    skip_frames: Vec<String>,
+
+    /// Record frames under this symbol path even if they would be skipped (repeatable)
+    #[arg(long, value_name = "PATH", global = true)]
+    keep_frames: Vec<String>,
+
+    /// Build the project as it is, without linking the profiler into it; only
+    /// programs that call `AllocationProfiler::enable` are then profiled
+    #[arg(long, global = true)]
+    no_inject: bool,
+
+    /// Read defaults from this file instead of `.alloc-profile.toml` or
+    /// `[package.metadata.alloc-profile]`
+    #[arg(long, value_name = "PATH", global = true)]
+    config: Option<PathBuf>,
+}
+
+/// Settings passed to the profiled program through environment variables
+struct ProfileSettings {
+    sample_interval_us: Option<u64>,
+    large_alloc_bytes: Option<usize>,
+    max_depth: Option<usize>,
+    skip_frames: Vec<String>,
+    keep_frames: Vec<String>,
+    // Whether cargo builds link the profiler into the project (see `inject`)
+    inject: bool,
+}
+
+impl ProfileSettings {
+    /// Set up a cargo build of the project, linking the profiler into it
+    /// unless injection is turned off
+    fn prepare_build(
+        &self,
+        cmd: &mut std::process::Command,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.apply(cmd);
+        if self.inject {
+            inject::prepare(cmd)?;
+        }
+        Ok(())
+    }
+
+    fn apply(&self, cmd: &mut std::process::Command) {
+        if let Some(interval) = self.sample_interval_us {
+            cmd.env(
+                "CARGO_ALLOC_PROFILE_SAMPLE_INTERVAL_US",
+                interval.to_string(),
+            );
+        }
+        if let Some(threshold) = self.large_alloc_bytes {
+            cmd.env(
+                "CARGO_ALLOC_PROFILE_LARGE_ALLOC_BYTES",
+                threshold.to_string(),
+            );
+        }
+        if let Some(depth) = self.max_depth {
+            cmd.env("CARGO_ALLOC_PROFILE_MAX_DEPTH", depth.to_string());
+        }
+        if !self.skip_frames.is_empty() {
+            cmd.env(
+                "CARGO_ALLOC_PROFILE_SKIP_FRAMES",
+                self.skip_frames.join(","),
+            );
+        }
+        if !self.keep_frames.is_empty() {
+            cmd.env(
+                "CARGO_ALLOC_PROFILE_KEEP_FRAMES",
+                self.keep_frames.join(","),
+            );
+        }
+    }
 }
 
 #[derive(Subcommand)]
@@ -135,6 +363,73 @@ enum Commands {
         /// Benchmark name filter
         bench_name: Option<String>,
 
+        /// Arguments to pass to cargo bench
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Run any dynamically linked program with its C heap profiled through
+    /// an LD_PRELOAD library
+    Exec {
+        /// Program to run, followed by its arguments
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Run a binary, tests or benchmarks with a live dashboard of their
+    /// allocations, then print the report. The program's standard input is
+    /// closed, as the dashboard reads the keyboard
+    Watch {
+        /// Time between updates from the program, in milliseconds
+        #[arg(long, default_value_t = 250)]
+        interval_ms: u64,
+
+        #[command(subcommand)]
+        target: WatchTarget,
+    },
+    /// Show a source file with the allocations attributed to each line
+    Annotate {
+        /// Source file to annotate
+        file: PathBuf,
+
+        /// Profiling data previously written with --save
+        #[arg(long)]
+        profile: PathBuf,
+
+        /// Also attribute allocations to lines that call into allocating code
+        #[arg(long)]
+        inclusive: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum WatchTarget {
+    /// Watch a binary or example
+    Run {
+        /// Name of the binary to run
+        #[arg(long)]
+        bin: Option<String>,
+
+        /// Name of the example to run
+        #[arg(long)]
+        example: Option<String>,
+
+        /// Arguments to pass to the binary
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Watch tests
+    Test {
+        /// Test name filter
+        test_name: Option<String>,
+
+        /// Arguments to pass to cargo test
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Watch benchmarks
+    Bench {
+        /// Benchmark name filter
+        bench_name: Option<String>,
+
         /// Arguments to pass to cargo bench
         #[arg(last = true)]
         args: Vec<String>,
@@ -142,47 +437,165 @@ enum Commands {
 }
 
 fn main() {
+    // Cargo runs this executable as the rustc wrapper of profiled builds
+    if let Some(code) = inject::run_as_rustc_wrapper() {
+        process::exit(code);
+    }
+
     let CargoCli::AllocProfile(args) = CargoCli::parse();
 
+    let config = match Config::load(args.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    // Command line flags take precedence over the configuration file
     let report_options = ReportOptions {
-        verbosity: args.verbose,
-        filter: args.filter.clone(),
-        format: args.output.clone().into(),
-        min_count: args.min_count,
-        threshold_bytes: args.threshold_bytes,
-        sort_by: args.sort_by.clone().into(),
-        limit: args.limit,
+        verbosity: if args.verbose > 0 {
+            args.verbose
+        } else {
+            config.verbose.unwrap_or(0)
+        },
+        filter: args.filter.clone().or(config.filter),
+        format: args
+            .output
+            .or(config.output)
+            .unwrap_or(OutputFormatArg::Text)
+            .into(),
+        min_count: args.min_count.or(config.min_count),
+        threshold_bytes: args.threshold_bytes.or(config.threshold_bytes),
+        sort_by: args
+            .sort_by
+            .or(config.sort_by)
+            .unwrap_or(SortByArg::Count)
+            .into(),
+        limit: args.limit.or(config.limit),
         save: args.save.clone(),
         compare: args.compare.clone(),
-        group_by: args.group_by.clone().into(),
+        group_by: args
+            .group_by
+            .or(config.group_by)
+            .unwrap_or(GroupByArg::Function)
+            .into(),
+        attribution: args
+            .attribute
+            .or(config.attribute)
+            .unwrap_or(AttributionArg::Allocation)
+            .into(),
+        symbols: args
+            .symbols
+            .or(config.symbols)
+            .unwrap_or(SymbolsArg::Full)
+            .into(),
+        output_file: args.output_file.clone(),
+        view: args.view.or(config.view).unwrap_or(ViewArg::Flat).into(),
+        prune_percent: args.prune.or(config.prune).unwrap_or(1.0),
+        focus: args.focus.clone().or(config.focus),
+        ignore: args.ignore.clone().or(config.ignore),
+        hide: args.hide.clone().or(config.hide),
     };
 
+    let mut settings = ProfileSettings {
+        sample_interval_us: args.sample_interval_us.or(config.sample_interval_us),
+        large_alloc_bytes: args.large_alloc_bytes.or(config.large_alloc_bytes),
+        max_depth: args.max_depth.or(config.max_depth),
+        skip_frames: if args.skip_frames.is_empty() {
+            config.skip_frames
+        } else {
+            args.skip_frames.clone()
+        },
+        keep_frames: if args.keep_frames.is_empty() {
+            config.keep_frames
+        } else {
+            args.keep_frames.clone()
+        },
+        inject: !(args.no_inject || config.no_inject),
+    };
+
+    // Container frames live in the alloc crate, which stacks skip by default,
+    // so type grouping implies `--keep-frames alloc` (see `GroupByArg::Type`)
+    if report_options.group_by == GroupBy::Type {
+        settings.keep_frames.push("alloc".to_string());
+    }
+
     let result = match args.command {
         Commands::Run {
             bin,
             example,
             args: run_args,
-        } => run_command(bin, example, run_args, report_options),
+        } => run_command(bin, example, run_args, &settings, report_options),
         Commands::Test {
             test_name,
             args: test_args,
-        } => test_command(test_name, test_args, report_options),
+        } => test_command(test_name, test_args, &settings, report_options),
         Commands::Bench {
             bench_name,
             args: bench_args,
-        } => bench_command(bench_name, bench_args, report_options),
+        } => bench_command(bench_name, bench_args, &settings, report_options),
+        Commands::Exec { command } => exec_command(command, &settings, report_options),
+        Commands::Watch {
+            interval_ms,
+            target,
+        } => watch_command(target, interval_ms, &settings, report_options),
+        Commands::Annotate {
+            file,
+            profile,
+            inclusive,
+        } => annotate_command(&file, &profile, inclusive, &report_options),
     };
 
     if let Err(e) = result {
         eprintln!("Error: {}", e);
-        process::exit(1);
+        // Exit as the profiled program did, so scripts see its failure
+        let code = e
+            .downcast_ref::<ProgramFailed>()
+            .map_or(1, ProgramFailed::exit_code);
+        process::exit(code);
     }
 }
 
+/// The profiled program exited unsuccessfully
+#[derive(Debug)]
+struct ProgramFailed {
+    what: &'static str,
+    status: process::ExitStatus,
+}
+
+impl ProgramFailed {
+    fn check(what: &'static str, status: process::ExitStatus) -> Option<ProgramFailed> {
+        (!status.success()).then_some(ProgramFailed { what, status })
+    }
+
+    /// The exit code of the program, or 128 plus the signal that killed it
+    /// as shells report it
+    fn exit_code(&self) -> i32 {
+        if let Some(code) = self.status.code() {
+            return code;
+        }
+        #[cfg(unix)]
+        if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&self.status) {
+            return 128 + signal;
+        }
+        1
+    }
+}
+
+impl std::fmt::Display for ProgramFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.what, self.status)
+    }
+}
+
+impl std::error::Error for ProgramFailed {}
+
 fn run_command(
     bin: Option<String>,
     example: Option<String>,
     args: Vec<String>,
+    settings: &ProfileSettings,
     report_options: ReportOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Only print status messages for text output
@@ -190,10 +603,6 @@ fn run_command(
         println!("🔍 Starting allocation profiling...\n");
     }
 
-    // Create a temporary file for the profiling output
-    let temp_dir = std::env::temp_dir();
-    let output_file = temp_dir.join(format!("cargo-alloc-profile-{}.json", process::id()));
-
     let mut cmd = std::process::Command::new("cargo");
     cmd.arg("run");
 
@@ -205,53 +614,167 @@ fn run_command(
 
     // Add environment variables to enable profiling and set output file
     cmd.env("CARGO_ALLOC_PROFILE", "1");
-    cmd.env("CARGO_ALLOC_PROFILE_OUTPUT", &output_file);
+    settings.prepare_build(&mut cmd)?;
 
     if !args.is_empty() {
         cmd.arg("--").args(args);
     }
 
-    // In JSON mode, suppress the program's output
-    if report_options.format == OutputFormat::Json {
+    // In machine-readable modes, suppress the program's output
+    if report_options.format != OutputFormat::Text {
         cmd.stdout(std::process::Stdio::null());
         cmd.stderr(std::process::Stdio::null());
     }
 
-    let status = cmd.status()?;
+    let collector = Collector::start(&mut cmd, None)?;
+    let status = collect::run_program(&mut cmd)?;
+    let processes = collector.finish();
+
+    print_collected_report(
+        processes,
+        ProgramFailed::check("Command failed", status),
+        report_options,
+        "No profiling data was generated",
+    )
+}
 
-    if !status.success() {
-        return Err("Command failed".into());
+fn exec_command(
+    command: Vec<String>,
+    settings: &ProfileSettings,
+    report_options: ReportOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !preload::SUPPORTED {
+        return Err("exec needs an ELF platform such as Linux".into());
     }
+    let library = preload::library()?;
 
-    // Read and display the profiling report
-    if output_file.exists() {
-        match std::fs::read_to_string(&output_file) {
-            Ok(json_data) => {
-                match serde_json::from_str::<cargo_alloc_profile::ProfileSnapshot>(&json_data) {
-                    Ok(snapshot) => {
-                        cargo_alloc_profile::Reporter::print_report(snapshot, report_options);
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to parse profiling data: {}", e);
-                    }
-                }
-            }
-            Err(e) => {
-                eprintln!("Failed to read profiling data: {}", e);
+    // Only print status messages for text output
+    if report_options.format == OutputFormat::Text {
+        println!("🔍 Starting allocation profiling...\n");
+    }
+
+    let mut cmd = std::process::Command::new(&command[0]);
+    cmd.args(&command[1..]);
+
+    // Load the interposer and enable profiling through it
+    cmd.env("LD_PRELOAD", &library);
+    cmd.env("CARGO_ALLOC_PROFILE", "1");
+    settings.apply(&mut cmd);
+
+    // In machine-readable modes, suppress the program's output
+    if report_options.format != OutputFormat::Text {
+        cmd.stdout(std::process::Stdio::null());
+        cmd.stderr(std::process::Stdio::null());
+    }
+
+    let collector = Collector::start(&mut cmd, None)?;
+    let status = collect::run_program(&mut cmd)?;
+    let processes = collector.finish();
+
+    print_collected_report(
+        processes,
+        ProgramFailed::check("Command failed", status),
+        report_options,
+        "No profiling data was generated (is the program statically linked?)",
+    )
+}
+
+fn watch_command(
+    target: WatchTarget,
+    interval_ms: u64,
+    settings: &ProfileSettings,
+    report_options: ReportOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = std::process::Command::new("cargo");
+    let args = match target {
+        WatchTarget::Run { bin, example, args } => {
+            cmd.arg("run");
+            if let Some(bin_name) = bin {
+                cmd.arg("--bin").arg(bin_name);
+            } else if let Some(example_name) = example {
+                cmd.arg("--example").arg(example_name);
             }
+            args
+        }
+        WatchTarget::Test { test_name, args } => {
+            cmd.arg("test").args(test_name);
+            args
         }
-        // Clean up the temp file
-        let _ = std::fs::remove_file(&output_file);
-    } else {
-        eprintln!("Warning: No profiling data was generated");
+        WatchTarget::Bench { bench_name, args } => {
+            cmd.arg("bench").args(bench_name);
+            args
+        }
+    };
+
+    cmd.env("CARGO_ALLOC_PROFILE", "1");
+    settings.prepare_build(&mut cmd)?;
+    if !args.is_empty() {
+        cmd.arg("--").args(args);
     }
 
-    Ok(())
+    let outcome = watch::run(&mut cmd, interval_ms, &report_options)?;
+
+    print_collected_report(
+        outcome.processes,
+        outcome
+            .status
+            .and_then(|status| ProgramFailed::check("Command failed", status)),
+        report_options,
+        "No profiling data was generated",
+    )
+}
+
+// Print the combined report of every profiled process. `cargo test` and
+// `bench` start one process per test or bench target. The report is printed
+// even when the program failed, and the failure is then returned.
+fn print_collected_report(
+    processes: Vec<collect::Process>,
+    failure: Option<ProgramFailed>,
+    report_options: ReportOptions,
+    no_data_warning: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let processes: Vec<_> = processes
+        .into_iter()
+        .filter(|process| process.snapshot.pid != 0)
+        .collect();
+    if processes.is_empty() {
+        eprintln!("Warning: {}", no_data_warning);
+        return failure.map_or(Ok(()), |failure| Err(failure.into()));
+    }
+
+    // Processes that ended without `write_report` leave a partial report
+    let mut processes = processes;
+    for process in &mut processes {
+        if !process.finished {
+            eprintln!(
+                "Warning: {} (pid {}) did not finish its report; showing its profile as of its last update",
+                process.snapshot.command, process.snapshot.pid
+            );
+            process.snapshot.partial = true;
+        } else if process.snapshot.partial {
+            eprintln!(
+                "Warning: {} (pid {}) ended without writing its report; showing its profile as it ended",
+                process.snapshot.command, process.snapshot.pid
+            );
+        }
+    }
+
+    // Every process the program started is part of the report
+    let snapshot = cargo_alloc_profile::ProfileSnapshot::combine(
+        processes
+            .into_iter()
+            .map(|process| process.snapshot)
+            .collect(),
+    );
+    cargo_alloc_profile::Reporter::print_report(snapshot, report_options);
+
+    failure.map_or(Ok(()), |failure| Err(failure.into()))
 }
 
 fn test_command(
     test_name: Option<String>,
     args: Vec<String>,
+    settings: &ProfileSettings,
     report_options: ReportOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Only print status messages for text output
@@ -259,10 +782,6 @@ fn test_command(
         println!("🔍 Running tests with allocation profiling...\n");
     }
 
-    // Create a temporary file for the profiling output
-    let temp_dir = std::env::temp_dir();
-    let output_file = temp_dir.join(format!("cargo-alloc-profile-{}.json", process::id()));
-
     let mut cmd = std::process::Command::new("cargo");
     cmd.arg("test");
 
@@ -270,51 +789,32 @@ fn test_command(
         cmd.arg(name);
     }
 
-    cmd.env("CARGO_ALLOC_PROFILE", "1");
-    cmd.env("CARGO_ALLOC_PROFILE_OUTPUT", &output_file);
     cmd.args(args);
+    cmd.env("CARGO_ALLOC_PROFILE", "1");
+    settings.prepare_build(&mut cmd)?;
 
-    // In JSON mode, suppress the program's output
-    if report_options.format == OutputFormat::Json {
+    // In machine-readable modes, suppress the program's output
+    if report_options.format != OutputFormat::Text {
         cmd.stdout(std::process::Stdio::null());
         cmd.stderr(std::process::Stdio::null());
     }
 
-    let status = cmd.status()?;
-
-    if !status.success() {
-        return Err("Tests failed".into());
-    }
-
-    // Read and display the profiling report
-    if output_file.exists() {
-        match std::fs::read_to_string(&output_file) {
-            Ok(json_data) => {
-                match serde_json::from_str::<cargo_alloc_profile::ProfileSnapshot>(&json_data) {
-                    Ok(snapshot) => {
-                        cargo_alloc_profile::Reporter::print_report(snapshot, report_options);
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to parse profiling data: {}", e);
-                    }
-                }
-            }
-            Err(e) => {
-                eprintln!("Failed to read profiling data: {}", e);
-            }
-        }
-        // Clean up the temp file
-        let _ = std::fs::remove_file(&output_file);
-    } else {
-        eprintln!("Warning: No profiling data was generated");
-    }
+    let collector = Collector::start(&mut cmd, None)?;
+    let status = collect::run_program(&mut cmd)?;
+    let processes = collector.finish();
 
-    Ok(())
+    print_collected_report(
+        processes,
+        ProgramFailed::check("Tests failed", status),
+        report_options,
+        "No profiling data was generated",
+    )
 }
 
 fn bench_command(
     bench_name: Option<String>,
     args: Vec<String>,
+    settings: &ProfileSettings,
     report_options: ReportOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Only print status messages for text output
@@ -322,10 +822,6 @@ fn bench_command(
         println!("🔍 Running benchmarks with allocation profiling...\n");
     }
 
-    // Create a temporary file for the profiling output
-    let temp_dir = std::env::temp_dir();
-    let output_file = temp_dir.join(format!("cargo-alloc-profile-{}.json", process::id()));
-
     let mut cmd = std::process::Command::new("cargo");
     cmd.arg("bench");
 
@@ -333,44 +829,46 @@ fn bench_command(
         cmd.arg(name);
     }
 
-    cmd.env("CARGO_ALLOC_PROFILE", "1");
-    cmd.env("CARGO_ALLOC_PROFILE_OUTPUT", &output_file);
     cmd.args(args);
+    cmd.env("CARGO_ALLOC_PROFILE", "1");
+    settings.prepare_build(&mut cmd)?;
 
-    // In JSON mode, suppress the program's output
-    if report_options.format == OutputFormat::Json {
+    // In machine-readable modes, suppress the program's output
+    if report_options.format != OutputFormat::Text {
         cmd.stdout(std::process::Stdio::null());
         cmd.stderr(std::process::Stdio::null());
     }
 
-    let status = cmd.status()?;
+    let collector = Collector::start(&mut cmd, None)?;
+    let status = collect::run_program(&mut cmd)?;
+    let processes = collector.finish();
 
-    if !status.success() {
-        return Err("Benchmarks failed".into());
-    }
+    print_collected_report(
+        processes,
+        ProgramFailed::check("Benchmarks failed", status),
+        report_options,
+        "No profiling data was generated",
+    )
+}
 
-    // Read and display the profiling report
-    if output_file.exists() {
-        match std::fs::read_to_string(&output_file) {
-            Ok(json_data) => {
-                match serde_json::from_str::<cargo_alloc_profile::ProfileSnapshot>(&json_data) {
-                    Ok(snapshot) => {
-                        cargo_alloc_profile::Reporter::print_report(snapshot, report_options);
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to parse profiling data: {}", e);
-                    }
-                }
-            }
-            Err(e) => {
-                eprintln!("Failed to read profiling data: {}", e);
-            }
-        }
-        // Clean up the temp file
-        let _ = std::fs::remove_file(&output_file);
-    } else {
-        eprintln!("Warning: No profiling data was generated");
-    }
+fn annotate_command(
+    file: &Path,
+    profile: &Path,
+    inclusive: bool,
+    report_options: &ReportOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let json_data = std::fs::read_to_string(profile)
+        .map_err(|e| format!("Failed to read {}: {}", profile.display(), e))?;
+    let snapshot = serde_json::from_str::<cargo_alloc_profile::ProfileSnapshot>(&json_data)
+        .map_err(|e| format!("Failed to parse profiling data: {}", e))?;
+
+    cargo_alloc_profile::Reporter::print_annotated_source(
+        &snapshot,
+        file,
+        inclusive,
+        report_options,
+    )
+    .map_err(|e| format!("Failed to annotate {}: {}", file.display(), e))?;
 
     Ok(())
 }
@@ -0,0 +1,56 @@
+//! Packages generated to build parts of this tool for a project: the shim
+//! that links the profiler into its programs and the `LD_PRELOAD` interposer.
+
+use std::path::Path;
+
+/// Write a package called `name` to `dir`, with `source` as its library and
+/// a dependency on `dependency`, the crate of this tool in `crate_dir` of its
+/// sources
+pub fn write(
+    dir: &Path,
+    name: &str,
+    source: &str,
+    dependency: &str,
+    crate_dir: &str,
+) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+
+    // Prefer the sources this executable was built from, so the package
+    // builds exactly the same version
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let source_dir = match crate_dir {
+        "" => manifest_dir.to_path_buf(),
+        _ => manifest_dir.join(crate_dir),
+    };
+    let version = if source_dir.join("Cargo.toml").is_file() {
+        format!("{{ path = {:?} }}", source_dir.display().to_string())
+    } else {
+        format!("\"={}\"", env!("CARGO_PKG_VERSION"))
+    };
+    let manifest = format!(
+        "[package]\n\
+         name = \"{}\"\n\
+         version = \"0.0.0\"\n\
+         edition = \"2024\"\n\
+         publish = false\n\
+         \n\
+         [lib]\n\
+         path = \"lib.rs\"\n\
+         \n\
+         [dependencies]\n\
+         {} = {}\n\
+         \n\
+         [workspace]\n",
+        name, dependency, version
+    );
+    write_if_changed(&dir.join("Cargo.toml"), &manifest)?;
+    write_if_changed(&dir.join("lib.rs"), source)
+}
+
+// Leave unchanged files alone so cargo does not rebuild the package
+fn write_if_changed(path: &Path, contents: &str) -> std::io::Result<()> {
+    if std::fs::read_to_string(path).is_ok_and(|current| current == contents) {
+        return Ok(());
+    }
+    std::fs::write(path, contents)
+}
@@ -0,0 +1,77 @@
+//! Locating the `LD_PRELOAD` interposer used by `cargo alloc-profile exec`.
+//!
+//! `cargo install` only installs this executable, so when the library is not
+//! found next to it, it is built from the `cargo-alloc-profile-preload` crate
+//! of the same version the first time `exec` needs it.
+
+use crate::package;
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Whether `exec` can interpose the allocator on this platform, which takes
+/// an ELF dynamic linker
+pub const SUPPORTED: bool = cfg!(all(unix, not(target_vendor = "apple")));
+
+/// The interposer: `CARGO_ALLOC_PROFILE_PRELOAD` if set, otherwise the
+/// library next to this executable or in a sibling `lib` directory, otherwise
+/// one built on demand
+pub fn library() -> Result<PathBuf, Box<dyn Error>> {
+    if let Some(path) = std::env::var_os("CARGO_ALLOC_PROFILE_PRELOAD") {
+        return Ok(PathBuf::from(path));
+    }
+
+    let name = library_name();
+    let exe = std::env::current_exe()?;
+    if let Some(dir) = exe.parent() {
+        for candidate in [dir.join(&name), dir.join("../lib").join(&name)] {
+            if candidate.exists() {
+                return Ok(candidate);
+            }
+        }
+    }
+
+    build()
+}
+
+fn library_name() -> String {
+    format!(
+        "{}cargo_alloc_profile_preload{}",
+        std::env::consts::DLL_PREFIX,
+        std::env::consts::DLL_SUFFIX
+    )
+}
+
+// Build the interposer in a cache directory shared by every project, through
+// a generated package that depends on it
+fn build() -> Result<PathBuf, Box<dyn Error>> {
+    let dir =
+        std::env::temp_dir().join(format!("cargo-alloc-profile-{}", env!("CARGO_PKG_VERSION")));
+    package::write(
+        &dir,
+        "cargo-alloc-profile-preload-build",
+        "",
+        "cargo-alloc-profile-preload",
+        "preload",
+    )?;
+
+    eprintln!("Building the allocation interposer (only needed once)...");
+    let target_dir = dir.join("target");
+    let status = Command::new("cargo")
+        .arg("build")
+        .arg("--quiet")
+        .arg("--release")
+        .arg("--manifest-path")
+        .arg(dir.join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .args(["-p", "cargo-alloc-profile-preload"])
+        .status()?;
+    if !status.success() {
+        return Err("Failed to build the allocation interposer; build it with \
+             `cargo build -p cargo-alloc-profile-preload` or set CARGO_ALLOC_PROFILE_PRELOAD"
+            .into());
+    }
+
+    Ok(target_dir.join("release").join(library_name()))
+}
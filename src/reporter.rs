@@ -1,5 +1,19 @@
-use crate::profiler::ProfileSnapshot;
+use crate::profiler::{AllocationSite, BenchmarkAllocations, ProcessSummary, ProfileSnapshot};
 use colored::*;
+use regex::Regex;
+
+mod annotate;
+mod call_tree;
+mod chrome_trace;
+mod crates;
+mod dhat;
+mod markdown;
+mod massif;
+mod pprof;
+mod sarif;
+mod speedscope;
+mod symbols;
+mod types;
 
 pub struct Reporter;
 
@@ -7,6 +21,20 @@ pub struct Reporter;
 pub enum OutputFormat {
     Text,
     Json,
+    /// Gzipped pprof `profile.proto`
+    Pprof,
+    /// speedscope.app file format
+    Speedscope,
+    /// Chrome Trace Event JSON for Perfetto and `chrome://tracing`
+    ChromeTrace,
+    /// Valgrind Massif `massif.out` snapshots
+    Massif,
+    /// DHAT JSON for Valgrind's `dh_view.html`
+    Dhat,
+    /// Markdown for pull request comments
+    Markdown,
+    /// SARIF 2.1.0 log for code scanning
+    Sarif,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -21,6 +49,55 @@ pub enum GroupBy {
     Function,
     Module,
     File,
+    /// The first crate along the stack outside the standard library
+    Crate,
+    /// The container or smart pointer type being allocated
+    Type,
+    /// The tracing span that was active when allocating
+    Span,
+}
+
+/// How symbol names are spelled in reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolStyle {
+    /// Names as recorded
+    Full,
+    /// No generic arguments, crate hashes or numbered closures
+    NoGenerics,
+    /// Like `NoGenerics`, with standard library module paths dropped
+    Short,
+}
+
+/// Which frame of a stack a site is reported under
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attribution {
+    /// The frame that made the allocation
+    Allocation,
+    /// The first frame whose source lies in the current workspace, falling
+    /// back to the allocating frame
+    Workspace,
+}
+
+/// How allocation sites are laid out in text and JSON reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum View {
+    /// One line per grouped site
+    Flat,
+    /// Call tree from the outermost caller down to the allocating function
+    Tree,
+    /// Call tree from the allocating function out to its callers
+    Inverted,
+}
+
+/// Totals of one entry of a grouped report
+#[derive(Debug, Clone)]
+pub struct SiteSummary {
+    /// Function, module, file, crate, type or span, depending on the grouping
+    pub name: String,
+    pub count: usize,
+    pub total_bytes: usize,
+    /// Bytes not freed yet
+    pub live_bytes: usize,
 }
 
 pub struct ReportOptions {
@@ -34,6 +111,18 @@ pub struct ReportOptions {
     pub save: Option<String>,
     pub compare: Option<String>,
     pub group_by: GroupBy,
+    pub attribution: Attribution,
+    pub symbols: SymbolStyle,
+    pub output_file: Option<String>,
+    pub view: View,
+    /// Tree nodes below this share of the total are hidden
+    pub prune_percent: f64,
+    /// Keep only stacks with a matching frame, re-rooted at that frame
+    pub focus: Option<Regex>,
+    /// Drop stacks with a matching frame
+    pub ignore: Option<Regex>,
+    /// Remove matching frames from every stack
+    pub hide: Option<Regex>,
 }
 
 impl Default for ReportOptions {
@@ -49,18 +138,69 @@ impl Default for ReportOptions {
             save: None,
             compare: None,
             group_by: GroupBy::Function,
+            attribution: Attribution::Allocation,
+            symbols: SymbolStyle::Full,
+            output_file: None,
+            view: View::Flat,
+            prune_percent: 1.0,
+            focus: None,
+            ignore: None,
+            hide: None,
         }
     }
 }
 
 impl Reporter {
     pub fn print_report(snapshot: ProfileSnapshot, options: ReportOptions) {
+        // Save if requested, before stack filters rewrite the data
+        if options.compare.is_none()
+            && let Some(ref save_file) = options.save
+            && let Err(e) = Self::save_snapshot(&snapshot, save_file)
+        {
+            eprintln!("Warning: Failed to save profiling data: {}", e);
+        }
+
+        let snapshot = Self::rewrite_stacks(snapshot, &options);
         match options.format {
             OutputFormat::Text => Self::print_text_report(snapshot, options),
             OutputFormat::Json => Self::print_json_report(snapshot, options),
+            OutputFormat::Markdown => Self::print_markdown_report(snapshot, options),
+            OutputFormat::Sarif => Self::write_sarif_report(snapshot, options),
+            OutputFormat::Pprof
+            | OutputFormat::Speedscope
+            | OutputFormat::ChromeTrace
+            | OutputFormat::Massif
+            | OutputFormat::Dhat => Self::write_export_report(snapshot, options),
         }
     }
 
+    /// Group the sites of a snapshot as a flat report would, applying the
+    /// stack and report filters but neither sorting nor `limit`
+    pub fn summarize(snapshot: ProfileSnapshot, options: &ReportOptions) -> Vec<SiteSummary> {
+        use std::collections::HashMap;
+
+        let snapshot = Self::rewrite_stacks(snapshot, options);
+        let mut grouped: HashMap<String, SiteSummary> = HashMap::new();
+        for site in snapshot.allocation_sites.values() {
+            let Some(key) = Self::site_key(site, options) else {
+                continue;
+            };
+            if !Self::passes_filters(site, &key, options) {
+                continue;
+            }
+            let summary = grouped.entry(key).or_insert_with_key(|key| SiteSummary {
+                name: key.clone(),
+                count: 0,
+                total_bytes: 0,
+                live_bytes: 0,
+            });
+            summary.count += site.count;
+            summary.total_bytes += site.total_bytes;
+            summary.live_bytes += site.live_bytes;
+        }
+        grouped.into_values().collect()
+    }
+
     fn print_text_report(snapshot: ProfileSnapshot, options: ReportOptions) {
         // Handle comparison if requested
         if let Some(ref compare_file) = options.compare {
@@ -68,11 +208,27 @@ impl Reporter {
             return;
         }
 
-        // Save if requested
-        if let Some(ref save_file) = options.save {
-            if let Err(e) = Self::save_snapshot(&snapshot, save_file) {
-                eprintln!("Warning: Failed to save profiling data: {}", e);
-            }
+        if snapshot.partial {
+            println!(
+                "\n{}",
+                "Partial report: the program ended without writing its report"
+                    .yellow()
+                    .bold()
+            );
+        }
+
+        if !snapshot.processes.is_empty() {
+            Self::print_process_tree(&snapshot);
+        }
+
+        if !snapshot.benchmarks.is_empty() {
+            Self::print_benchmark_table(&snapshot.benchmarks);
+        }
+
+        if options.view != View::Flat {
+            let sites = Self::filtered_sites(&snapshot, &options);
+            call_tree::print(&call_tree::build(&sites, options.view), &options);
+            return;
         }
 
         println!("\n{}", "Allocation Profile:".bright_blue().bold());
@@ -92,10 +248,10 @@ impl Reporter {
                 print!(" ({:.2} KB)", *total_bytes as f64 / 1024.0);
             }
 
-            if options.verbosity >= 2 {
-                if let Some(frame) = frames.first() {
-                    print!(" [{}]", frame.dimmed());
-                }
+            if options.verbosity >= 2
+                && let Some(frame) = Self::attributed_frame(frames, &options)
+            {
+                print!(" [{}]", frame.dimmed());
             }
 
             println!();
@@ -120,6 +276,57 @@ impl Reporter {
         }
     }
 
+    // Totals of each process, indented under the process that started it,
+    // and of all of them together
+    fn print_process_tree(snapshot: &ProfileSnapshot) {
+        println!("\n{}", "Processes:".bright_blue().bold());
+        for (process, depth) in process_tree(&snapshot.processes) {
+            println!(
+                "  {}{} {}: {} allocations, {:.2} KB, peak {:.2} KB",
+                "  ".repeat(depth),
+                process.pid.to_string().bright_white(),
+                process.command.dimmed(),
+                process.total_allocations.to_string().bright_green(),
+                process.total_bytes_allocated as f64 / 1024.0,
+                process.peak_memory as f64 / 1024.0,
+            );
+        }
+        println!(
+            "  {}: {} allocations, {:.2} KB, peak {:.2} KB",
+            "Combined".bold(),
+            snapshot.total_allocations.to_string().bright_green(),
+            snapshot.total_bytes_allocated as f64 / 1024.0,
+            snapshot.peak_memory as f64 / 1024.0,
+        );
+    }
+
+    // Allocations per iteration of each benchmark
+    fn print_benchmark_table(benchmarks: &[BenchmarkAllocations]) {
+        let width = benchmarks
+            .iter()
+            .map(|benchmark| benchmark.id.chars().count())
+            .max()
+            .unwrap_or(0)
+            .max("Benchmark".len());
+
+        println!("\n{}", "Benchmarks:".bright_blue().bold());
+        println!(
+            "  {:<width$}  {:>14}  {:>14}",
+            "Benchmark".bold(),
+            "allocs/iter".bold(),
+            "bytes/iter".bold(),
+        );
+        for benchmark in benchmarks {
+            let (allocations, bytes) = per_iteration(benchmark);
+            println!(
+                "  {:<width$}  {:>14}  {:>14}",
+                benchmark.id.bright_white(),
+                format!("{:.2}", allocations).bright_green(),
+                format!("{:.1}", bytes),
+            );
+        }
+    }
+
     fn print_json_report(snapshot: ProfileSnapshot, options: ReportOptions) {
         use serde_json::json;
 
@@ -129,11 +336,46 @@ impl Reporter {
             return;
         }
 
-        // Save if requested
-        if let Some(ref save_file) = options.save {
-            if let Err(e) = Self::save_snapshot(&snapshot, save_file) {
-                eprintln!("Warning: Failed to save profiling data: {}", e);
+        let summary = json!({
+            "total_allocations": snapshot.total_allocations,
+            "total_deallocations": snapshot.total_deallocations,
+            "total_bytes_allocated": snapshot.total_bytes_allocated,
+            "peak_memory": snapshot.peak_memory,
+            "current_memory": snapshot.current_memory,
+        });
+
+        let benchmarks: Vec<_> = snapshot
+            .benchmarks
+            .iter()
+            .map(|benchmark| {
+                let (allocations, bytes) = per_iteration(benchmark);
+                json!({
+                    "id": benchmark.id,
+                    "iterations": benchmark.iterations,
+                    "allocations_per_iteration": allocations,
+                    "bytes_per_iteration": bytes,
+                })
+            })
+            .collect();
+
+        if options.view != View::Flat {
+            let sites = Self::filtered_sites(&snapshot, &options);
+            let root = call_tree::build(&sites, options.view);
+            let mut output = json!({
+                "call_tree": call_tree::to_json(&root, &root, &options),
+                "summary": summary,
+            });
+            if !benchmarks.is_empty() {
+                output["benchmarks"] = json!(benchmarks);
+            }
+            if !snapshot.processes.is_empty() {
+                output["processes"] = json!(snapshot.processes);
+            }
+            if snapshot.partial {
+                output["partial"] = json!(true);
             }
+            println!("{}", serde_json::to_string_pretty(&output).unwrap());
+            return;
         }
 
         let sites = Self::prepare_sites(&snapshot, &options);
@@ -149,10 +391,10 @@ impl Reporter {
                 alloc_data["total_bytes"] = json!(total_bytes);
             }
 
-            if options.verbosity >= 2 {
-                if let Some(frame) = frames.first() {
-                    alloc_data["location"] = json!(frame);
-                }
+            if options.verbosity >= 2
+                && let Some(frame) = Self::attributed_frame(frames, &options)
+            {
+                alloc_data["location"] = json!(frame);
             }
 
             if options.verbosity >= 3 {
@@ -162,20 +404,165 @@ impl Reporter {
             allocations.push(alloc_data);
         }
 
-        let output = json!({
+        let mut output = json!({
             "allocations": allocations,
-            "summary": {
-                "total_allocations": snapshot.total_allocations,
-                "total_deallocations": snapshot.total_deallocations,
-                "total_bytes_allocated": snapshot.total_bytes_allocated,
-                "peak_memory": snapshot.peak_memory,
-                "current_memory": snapshot.current_memory,
-            }
+            "summary": summary,
         });
+        if !benchmarks.is_empty() {
+            output["benchmarks"] = json!(benchmarks);
+        }
+        if !snapshot.processes.is_empty() {
+            output["processes"] = json!(snapshot.processes);
+        }
+        if snapshot.partial {
+            output["partial"] = json!(true);
+        }
 
         println!("{}", serde_json::to_string_pretty(&output).unwrap());
     }
 
+    fn print_markdown_report(snapshot: ProfileSnapshot, options: ReportOptions) {
+        // Handle comparison if requested
+        if let Some(ref compare_file) = options.compare {
+            match Self::load_baseline(compare_file, &options) {
+                Ok(baseline) => {
+                    let comparisons = Self::compare_sites(&snapshot, &baseline, &options);
+                    let report =
+                        markdown::render_comparison(&snapshot, &baseline, &comparisons, &options);
+                    Self::write_output(report.as_bytes(), &options);
+                }
+                Err(e) => eprintln!("Error: {}", e),
+            }
+            return;
+        }
+
+        let sites = Self::prepare_sites(&snapshot, &options);
+        let report = markdown::render(&snapshot, &sites, &options);
+        Self::write_output(report.as_bytes(), &options);
+    }
+
+    fn write_sarif_report(snapshot: ProfileSnapshot, options: ReportOptions) {
+        let baseline = match options.compare {
+            Some(ref compare_file) => match Self::load_baseline(compare_file, &options) {
+                Ok(baseline) => Some(baseline),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        let sites = Self::filtered_sites(&snapshot, &options);
+        match sarif::render(&sites, baseline.as_ref(), &options) {
+            Ok(log) => Self::write_output(log.as_bytes(), &options),
+            Err(e) => eprintln!("Error: Failed to render report: {}", e),
+        }
+    }
+
+    /// Print a source file with the allocations attributed to each of its lines
+    pub fn print_annotated_source(
+        snapshot: &ProfileSnapshot,
+        source_path: &std::path::Path,
+        inclusive: bool,
+        options: &ReportOptions,
+    ) -> std::io::Result<()> {
+        let snapshot = Self::rewrite_stacks(snapshot.clone(), options);
+        let sites = Self::filtered_sites(&snapshot, options);
+        annotate::print(&sites, source_path, inclusive)
+    }
+
+    /// Render the snapshot in a format meant for an external viewer
+    fn write_export_report(snapshot: ProfileSnapshot, options: ReportOptions) {
+        let sites = Self::filtered_sites(&snapshot, &options);
+        let rendered = match options.format {
+            OutputFormat::Pprof => pprof::encode(&sites),
+            OutputFormat::Speedscope => speedscope::render(&sites).map(String::into_bytes),
+            OutputFormat::ChromeTrace => chrome_trace::render(&snapshot).map(String::into_bytes),
+            OutputFormat::Massif => massif::render(&snapshot, &sites).map(String::into_bytes),
+            OutputFormat::Dhat => dhat::render(&snapshot, &sites).map(String::into_bytes),
+            OutputFormat::Text
+            | OutputFormat::Json
+            | OutputFormat::Markdown
+            | OutputFormat::Sarif => {
+                unreachable!("not an export format")
+            }
+        };
+
+        match rendered {
+            Ok(data) => Self::write_output(&data, &options),
+            Err(e) => eprintln!("Error: Failed to render report: {}", e),
+        }
+    }
+
+    /// Write a rendered report to `--output-file`, or to stdout if none was given
+    fn write_output(data: &[u8], options: &ReportOptions) {
+        use std::io::Write;
+
+        if let Some(ref path) = options.output_file {
+            match std::fs::write(path, data) {
+                Ok(()) => eprintln!("✓ Report written to {}", path),
+                Err(e) => eprintln!("Error: Failed to write report to {}: {}", path, e),
+            }
+        } else {
+            let mut stdout = std::io::stdout().lock();
+            if let Err(e) = stdout.write_all(data).and_then(|_| stdout.flush()) {
+                eprintln!("Error: Failed to write report: {}", e);
+            }
+        }
+    }
+
+    /// Split a recorded frame into its function name, source file and line.
+    ///
+    /// Frames look like `"module::function (path/to/file.rs:42)"`, or just the
+    /// function name when no debug info was available.
+    pub(crate) fn split_frame(frame: &str) -> (&str, Option<&str>, Option<u32>) {
+        if let Some(inner) = frame.strip_suffix(')')
+            && let Some(open) = inner.rfind(" (")
+        {
+            let function = inner[..open].trim();
+            let location = &inner[open + 2..];
+            if let Some(colon) = location.rfind(':')
+                && let Ok(line) = location[colon + 1..].parse()
+            {
+                return (function, Some(&location[..colon]), Some(line));
+            }
+            return (function, Some(location), None);
+        }
+        (frame.trim(), None, None)
+    }
+
+    /// Remove the `[1a2b3c4d]` crate disambiguators that v0 mangling adds
+    /// to paths, as in `alloc[1a2b3c4d]::vec::Vec`
+    pub(crate) fn strip_disambiguators(name: &str) -> String {
+        let mut stripped = String::with_capacity(name.len());
+        let mut rest = name;
+        while let Some(open) = rest.find('[') {
+            let hash_len = rest[open + 1..].find(']').filter(|&len| {
+                len > 0
+                    && rest[open + 1..open + 1 + len]
+                        .chars()
+                        .all(|c| c.is_ascii_hexdigit())
+            });
+            stripped.push_str(&rest[..open]);
+            match hash_len {
+                Some(len) => rest = &rest[open + len + 2..],
+                None => {
+                    stripped.push('[');
+                    rest = &rest[open + 1..];
+                }
+            }
+        }
+        stripped.push_str(rest);
+        stripped
+    }
+
+    /// Frames are stored with `‹›` in place of angle brackets for display;
+    /// external viewers expect the real symbol names
+    pub(crate) fn restore_generics(name: &str) -> String {
+        name.replace('‹', "<").replace('›', ">")
+    }
+
     fn extract_function_name(frame: &str) -> String {
         // Extract just the function name without file path
         // Input: "cargo_alloc_profile::allocator::impl$0::alloc (C:\path\to\file.rs:27)"
@@ -203,19 +590,173 @@ impl Reporter {
 
     fn extract_file_name(frame: &str) -> String {
         // Extract file path from frame
-        if let Some(start) = frame.find('(') {
-            if let Some(end) = frame.rfind(':') {
-                let path = &frame[start + 1..end];
-                // Get just the filename, not the full path
-                if let Some(last_sep) = path.rfind(['\\', '/']) {
-                    return path[last_sep + 1..].to_string();
-                }
-                return path.to_string();
+        if let Some(start) = frame.find('(')
+            && let Some(end) = frame.rfind(':')
+        {
+            let path = &frame[start + 1..end];
+            // Get just the filename, not the full path
+            if let Some(last_sep) = path.rfind(['\\', '/']) {
+                return path[last_sep + 1..].to_string();
             }
+            return path.to_string();
         }
         "unknown".to_string()
     }
 
+    fn group_key(frame: &str, group_by: GroupBy) -> String {
+        match group_by {
+            GroupBy::Function => Self::extract_function_name(frame),
+            GroupBy::Module => Self::extract_module_name(frame),
+            GroupBy::File => Self::extract_file_name(frame),
+            GroupBy::Crate => crates::CRATES.crate_of(frame).to_string(),
+            GroupBy::Type => types::frame_type(frame).unwrap_or_else(|| "unknown".to_string()),
+            // Spans belong to whole stacks rather than frames
+            GroupBy::Span => Self::extract_function_name(frame),
+        }
+    }
+
+    /// The frame a stack is reported under
+    fn attributed_frame<'a>(frames: &'a [String], options: &ReportOptions) -> Option<&'a String> {
+        match options.attribution {
+            Attribution::Allocation => frames.first(),
+            Attribution::Workspace => frames
+                .iter()
+                .find(|frame| crates::CRATES.is_workspace_frame(frame))
+                .or(frames.first()),
+        }
+    }
+
+    /// The key a whole stack is grouped under
+    fn site_key(site: &AllocationSite, options: &ReportOptions) -> Option<String> {
+        let frames = &site.frames;
+        match options.group_by {
+            GroupBy::Crate => crates::CRATES.attribute(frames).map(|id| id.to_string()),
+            GroupBy::Type if frames.is_empty() => None,
+            GroupBy::Type => {
+                Some(types::allocated_type(frames).unwrap_or_else(|| "unknown".to_string()))
+            }
+            GroupBy::Span if frames.is_empty() => None,
+            GroupBy::Span => Some(site.span.clone().unwrap_or_else(|| "(no span)".to_string())),
+            GroupBy::Function | GroupBy::Module | GroupBy::File => {
+                Self::attributed_frame(frames, options)
+                    .map(|frame| Self::group_key(frame, options.group_by))
+            }
+        }
+    }
+
+    fn passes_filters(site: &AllocationSite, key: &str, options: &ReportOptions) -> bool {
+        // Apply filter if specified
+        if let Some(ref filter) = options.filter
+            && !key.to_lowercase().contains(&filter.to_lowercase())
+        {
+            return false;
+        }
+
+        // Apply min_count filter
+        if let Some(min_count) = options.min_count
+            && site.count < min_count
+        {
+            return false;
+        }
+
+        // Apply threshold_bytes filter
+        if let Some(threshold) = options.threshold_bytes
+            && site.total_bytes < threshold
+        {
+            return false;
+        }
+
+        true
+    }
+
+    fn frame_matches(pattern: &Regex, frame: &str) -> bool {
+        pattern.is_match(&Self::restore_generics(frame))
+    }
+
+    /// Rewrite every stack according to `--symbols`, `--ignore`, `--focus`
+    /// and `--hide`, merging sites whose stacks become identical
+    fn rewrite_stacks(mut snapshot: ProfileSnapshot, options: &ReportOptions) -> ProfileSnapshot {
+        // Type grouping reads the generic arguments, so it keeps full names
+        let style = match options.group_by {
+            GroupBy::Type => SymbolStyle::Full,
+            _ => options.symbols,
+        };
+        if style == SymbolStyle::Full
+            && options.focus.is_none()
+            && options.ignore.is_none()
+            && options.hide.is_none()
+        {
+            return snapshot;
+        }
+
+        use std::collections::HashMap;
+        use std::collections::hash_map::Entry;
+
+        for large in &mut snapshot.large_allocations {
+            for frame in &mut large.frames {
+                *frame = symbols::simplify(frame, style);
+            }
+        }
+
+        let mut sites: HashMap<String, AllocationSite> = HashMap::new();
+        for mut site in std::mem::take(&mut snapshot.allocation_sites).into_values() {
+            for frame in &mut site.frames {
+                *frame = symbols::simplify(frame, style);
+            }
+
+            if let Some(ref ignore) = options.ignore
+                && site.frames.iter().any(|f| Self::frame_matches(ignore, f))
+            {
+                continue;
+            }
+
+            if let Some(ref focus) = options.focus {
+                // Frames run from the allocation outwards, so the last match
+                // is the outermost one
+                match site
+                    .frames
+                    .iter()
+                    .rposition(|f| Self::frame_matches(focus, f))
+                {
+                    Some(root) => site.frames.truncate(root + 1),
+                    None => continue,
+                }
+            }
+
+            if let Some(ref hide) = options.hide {
+                site.frames.retain(|f| !Self::frame_matches(hide, f));
+                if site.frames.is_empty() {
+                    continue;
+                }
+            }
+
+            match sites.entry(site.key()) {
+                Entry::Occupied(mut entry) => entry.get_mut().merge(&site),
+                Entry::Vacant(entry) => {
+                    entry.insert(site);
+                }
+            }
+        }
+
+        snapshot.allocation_sites = sites;
+        snapshot
+    }
+
+    /// Allocation sites that pass the report filters, with their full stacks
+    fn filtered_sites<'a>(
+        snapshot: &'a ProfileSnapshot,
+        options: &ReportOptions,
+    ) -> Vec<&'a AllocationSite> {
+        snapshot
+            .allocation_sites
+            .values()
+            .filter(|site| match Self::site_key(site, options) {
+                Some(key) => Self::passes_filters(site, &key, options),
+                None => false,
+            })
+            .collect()
+    }
+
     fn prepare_sites(
         snapshot: &ProfileSnapshot,
         options: &ReportOptions,
@@ -226,33 +767,10 @@ impl Reporter {
         let mut grouped: HashMap<String, (usize, usize, Vec<String>)> = HashMap::new();
 
         for site in snapshot.allocation_sites.values() {
-            if let Some(frame) = site.frames.first() {
-                // Determine grouping key
-                let key = match options.group_by {
-                    GroupBy::Function => Self::extract_function_name(frame),
-                    GroupBy::Module => Self::extract_module_name(frame),
-                    GroupBy::File => Self::extract_file_name(frame),
-                };
-
-                // Apply filter if specified
-                if let Some(ref filter) = options.filter {
-                    if !key.to_lowercase().contains(&filter.to_lowercase()) {
-                        continue;
-                    }
-                }
-
-                // Apply min_count filter
-                if let Some(min_count) = options.min_count {
-                    if site.count < min_count {
-                        continue;
-                    }
-                }
-
-                // Apply threshold_bytes filter
-                if let Some(threshold) = options.threshold_bytes {
-                    if site.total_bytes < threshold {
-                        continue;
-                    }
+            // Determine grouping key
+            if let Some(key) = Self::site_key(site, options) {
+                if !Self::passes_filters(site, &key, options) {
+                    continue;
                 }
 
                 grouped
@@ -273,8 +791,8 @@ impl Reporter {
 
         // Sort based on sort_by option
         match options.sort_by {
-            SortBy::Count => sites.sort_by(|a, b| b.1.cmp(&a.1)),
-            SortBy::Size => sites.sort_by(|a, b| b.2.cmp(&a.2)),
+            SortBy::Count => sites.sort_by_key(|site| std::cmp::Reverse(site.1)),
+            SortBy::Size => sites.sort_by_key(|site| std::cmp::Reverse(site.2)),
             SortBy::Name => sites.sort_by(|a, b| a.0.cmp(&b.0)),
         }
 
@@ -293,22 +811,77 @@ impl Reporter {
         Ok(())
     }
 
+    /// Load a saved snapshot and filter its stacks like the current run's
+    fn load_baseline(path: &str, options: &ReportOptions) -> Result<ProfileSnapshot, String> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read comparison file: {}", e))?;
+        let baseline = serde_json::from_str(&data)
+            .map_err(|e| format!("Failed to parse comparison file: {}", e))?;
+        Ok(Self::rewrite_stacks(baseline, options))
+    }
+
+    /// Match the current run's grouped sites against a baseline snapshot.
+    ///
+    /// Current sites come first, in report order, followed by sites that only
+    /// exist in the baseline.
+    fn compare_sites(
+        snapshot: &ProfileSnapshot,
+        baseline: &ProfileSnapshot,
+        options: &ReportOptions,
+    ) -> Vec<SiteComparison> {
+        use std::collections::HashMap;
+
+        // Build maps for easier comparison
+        let mut baseline_map: HashMap<String, (usize, usize, Vec<String>)> = HashMap::new();
+        for site in baseline.allocation_sites.values() {
+            if let Some(key) = Self::site_key(site, options) {
+                baseline_map
+                    .entry(key)
+                    .and_modify(|(count, bytes, _)| {
+                        *count += site.count;
+                        *bytes += site.total_bytes;
+                    })
+                    .or_insert((site.count, site.total_bytes, site.frames.clone()));
+            }
+        }
+
+        let mut comparisons: Vec<SiteComparison> = Self::prepare_sites(snapshot, options)
+            .into_iter()
+            .map(|(name, count, bytes, frames)| SiteComparison {
+                baseline: baseline_map
+                    .remove(&name)
+                    .map(|(count, bytes, _)| (count, bytes)),
+                current: Some((count, bytes)),
+                name,
+                frames,
+            })
+            .collect();
+
+        // Whatever is left in the baseline no longer allocates
+        let mut removed: Vec<_> = baseline_map.into_iter().collect();
+        removed.sort_by(|a, b| a.0.cmp(&b.0));
+        comparisons.extend(removed.into_iter().map(|(name, (count, bytes, frames))| {
+            SiteComparison {
+                name,
+                baseline: Some((count, bytes)),
+                current: None,
+                frames,
+            }
+        }));
+
+        comparisons
+    }
+
     fn print_comparison_report(
         snapshot: &ProfileSnapshot,
         compare_file: &str,
         options: &ReportOptions,
     ) {
         // Load the comparison snapshot
-        let compare_snapshot = match std::fs::read_to_string(compare_file) {
-            Ok(data) => match serde_json::from_str::<ProfileSnapshot>(&data) {
-                Ok(s) => s,
-                Err(e) => {
-                    eprintln!("Error: Failed to parse comparison file: {}", e);
-                    return;
-                }
-            },
+        let compare_snapshot = match Self::load_baseline(compare_file, options) {
+            Ok(s) => s,
             Err(e) => {
-                eprintln!("Error: Failed to read comparison file: {}", e);
+                eprintln!("Error: {}", e);
                 return;
             }
         };
@@ -320,80 +893,108 @@ impl Reporter {
             "Baseline".bright_yellow()
         );
 
-        // Build maps for easier comparison
-        use std::collections::HashMap;
-        let mut baseline_map: HashMap<String, (usize, usize)> = HashMap::new();
-        for site in compare_snapshot.allocation_sites.values() {
-            if let Some(frame) = site.frames.first() {
-                let key = match options.group_by {
-                    GroupBy::Function => Self::extract_function_name(frame),
-                    GroupBy::Module => Self::extract_module_name(frame),
-                    GroupBy::File => Self::extract_file_name(frame),
-                };
-                baseline_map
-                    .entry(key)
-                    .and_modify(|(count, bytes)| {
-                        *count += site.count;
-                        *bytes += site.total_bytes;
-                    })
-                    .or_insert((site.count, site.total_bytes));
-            }
-        }
+        for comparison in Self::compare_sites(snapshot, &compare_snapshot, options) {
+            let name = &comparison.name;
+            match (comparison.baseline, comparison.current) {
+                (Some((baseline_count, baseline_bytes)), Some((current_count, current_bytes))) => {
+                    let count_diff = current_count as isize - baseline_count as isize;
+                    let bytes_diff = current_bytes as isize - baseline_bytes as isize;
+
+                    let count_str = if count_diff > 0 {
+                        format!("+{}", count_diff).bright_red()
+                    } else if count_diff < 0 {
+                        format!("{}", count_diff).bright_green()
+                    } else {
+                        "±0".normal()
+                    };
+
+                    let bytes_str = if bytes_diff > 0 {
+                        format!("(+{:.2} KB)", bytes_diff as f64 / 1024.0).bright_red()
+                    } else if bytes_diff < 0 {
+                        format!("({:.2} KB)", bytes_diff as f64 / 1024.0).bright_green()
+                    } else {
+                        "(±0 KB)".normal()
+                    };
 
-        let current_sites = Self::prepare_sites(snapshot, options);
-
-        for (name, current_count, current_bytes, _frames) in current_sites.iter() {
-            if let Some((baseline_count, baseline_bytes)) = baseline_map.get(name) {
-                let count_diff = *current_count as isize - *baseline_count as isize;
-                let bytes_diff = *current_bytes as isize - *baseline_bytes as isize;
-
-                let count_str = if count_diff > 0 {
-                    format!("+{}", count_diff).bright_red()
-                } else if count_diff < 0 {
-                    format!("{}", count_diff).bright_green()
-                } else {
-                    "±0".normal()
-                };
-
-                let bytes_str = if bytes_diff > 0 {
-                    format!("(+{:.2} KB)", bytes_diff as f64 / 1024.0).bright_red()
-                } else if bytes_diff < 0 {
-                    format!("({:.2} KB)", bytes_diff as f64 / 1024.0).bright_green()
-                } else {
-                    "(±0 KB)".normal()
-                };
-
-                println!(
-                    "{}: {} → {} {} {}",
-                    name.bright_white(),
-                    baseline_count,
-                    current_count,
-                    count_str,
-                    bytes_str
-                );
-            } else {
-                // New allocation site
-                println!(
-                    "{}: {} {} {}",
-                    name.bright_white(),
-                    format!("{}", current_count).bright_green(),
-                    "[NEW]".bright_yellow(),
-                    format!("({:.2} KB)", *current_bytes as f64 / 1024.0)
-                );
+                    println!(
+                        "{}: {} → {} {} {}",
+                        name.bright_white(),
+                        baseline_count,
+                        current_count,
+                        count_str,
+                        bytes_str
+                    );
+                }
+                (None, Some((current_count, current_bytes))) => {
+                    // New allocation site
+                    println!(
+                        "{}: {} {} ({:.2} KB)",
+                        name.bright_white(),
+                        current_count.to_string().bright_green(),
+                        "[NEW]".bright_yellow(),
+                        current_bytes as f64 / 1024.0
+                    );
+                }
+                (Some((baseline_count, baseline_bytes)), None) => {
+                    // Removed allocation site
+                    println!(
+                        "{}: {} {} {}",
+                        name.dimmed(),
+                        baseline_count,
+                        "[REMOVED]".bright_cyan(),
+                        format!("({:.2} KB)", baseline_bytes as f64 / 1024.0).dimmed()
+                    );
+                }
+                (None, None) => {}
             }
         }
+    }
+}
 
-        // Show removed allocation sites
-        for (name, (baseline_count, baseline_bytes)) in baseline_map.iter() {
-            if !current_sites.iter().any(|(n, _, _, _)| n == name) {
-                println!(
-                    "{}: {} {} {}",
-                    name.dimmed(),
-                    baseline_count,
-                    "[REMOVED]".bright_cyan(),
-                    format!("({:.2} KB)", *baseline_bytes as f64 / 1024.0).dimmed()
-                );
-            }
+/// A grouped allocation site matched between the current run and a baseline
+pub(crate) struct SiteComparison {
+    pub name: String,
+    /// `(count, bytes)` in the baseline, if the site allocated there
+    pub baseline: Option<(usize, usize)>,
+    /// `(count, bytes)` in the current run, if the site still allocates
+    pub current: Option<(usize, usize)>,
+    pub frames: Vec<String>,
+}
+
+// Allocations and bytes per iteration of a benchmark
+fn per_iteration(benchmark: &BenchmarkAllocations) -> (f64, f64) {
+    let iterations = benchmark.iterations.max(1) as f64;
+    (
+        benchmark.allocations as f64 / iterations,
+        benchmark.bytes as f64 / iterations,
+    )
+}
+
+// Processes in depth-first order with their depth in the tree. Processes
+// whose parent was not profiled are roots.
+fn process_tree(processes: &[ProcessSummary]) -> Vec<(&ProcessSummary, usize)> {
+    fn visit<'a>(
+        process: &'a ProcessSummary,
+        depth: usize,
+        processes: &'a [ProcessSummary],
+        tree: &mut Vec<(&'a ProcessSummary, usize)>,
+    ) {
+        tree.push((process, depth));
+        for child in processes
+            .iter()
+            .filter(|child| child.parent_pid == process.pid && child.pid != process.pid)
+        {
+            visit(child, depth + 1, processes, tree);
         }
     }
+
+    let mut tree = Vec::new();
+    for root in processes.iter().filter(|process| {
+        !processes
+            .iter()
+            .any(|parent| parent.pid == process.parent_pid && parent.pid != process.pid)
+    }) {
+        visit(root, 0, processes, &mut tree);
+    }
+    tree
 }
@@ -0,0 +1,116 @@
+//! Source listing annotated with the allocations attributed to each line.
+
+use super::Reporter;
+use crate::profiler::AllocationSite;
+use colored::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Print `source_path` with each line's allocation count and bytes.
+///
+/// A site is attributed to the innermost frame in the file, or with
+/// `inclusive` to every line of the file on its stack, so callers also carry
+/// the allocations made beneath them.
+pub(crate) fn print(
+    sites: &[&AllocationSite],
+    source_path: &Path,
+    inclusive: bool,
+) -> std::io::Result<()> {
+    let source = std::fs::read_to_string(source_path)?;
+    let canonical = source_path.canonicalize().ok();
+
+    let mut lines: HashMap<u32, (usize, usize)> = HashMap::new();
+    let mut matches_file: HashMap<&str, bool> = HashMap::new();
+    for site in sites {
+        let mut seen = Vec::new();
+        for frame in &site.frames {
+            let (_, Some(file), Some(line)) = Reporter::split_frame(frame) else {
+                continue;
+            };
+            let in_file = *matches_file
+                .entry(file)
+                .or_insert_with(|| same_file(Path::new(file), source_path, canonical.as_ref()));
+            if !in_file || seen.contains(&line) {
+                continue;
+            }
+
+            seen.push(line);
+            let entry = lines.entry(line).or_default();
+            entry.0 += site.count;
+            entry.1 += site.total_bytes;
+            if !inclusive {
+                break;
+            }
+        }
+    }
+
+    let hottest = lines.values().map(|(_, bytes)| *bytes).max().unwrap_or(0);
+    let width = source.lines().count().to_string().len();
+
+    println!(
+        "\n{} {}{}",
+        "Allocation Annotations:".bright_blue().bold(),
+        source_path.display(),
+        if inclusive { " (inclusive)" } else { "" }
+    );
+    println!("{:>8} {:>11} │", "Count", "Bytes");
+
+    for (index, text) in source.lines().enumerate() {
+        let number = index as u32 + 1;
+        match lines.get(&number) {
+            Some(&(count, bytes)) => {
+                let stats = format!(
+                    "{:>8} {:>11} │ {:>width$}  {}",
+                    count,
+                    format!("{:.2} KB", bytes as f64 / 1024.0),
+                    number,
+                    text,
+                    width = width
+                );
+                // Highlight the lines responsible for most of the bytes
+                if bytes * 2 >= hottest {
+                    println!("{}", stats.bright_red());
+                } else {
+                    println!("{}", stats.bright_yellow());
+                }
+            }
+            None => println!(
+                "{:>8} {:>11} │ {:>width$}  {}",
+                ".".dimmed(),
+                ".".dimmed(),
+                number.to_string().dimmed(),
+                text,
+                width = width
+            ),
+        }
+    }
+
+    if lines.is_empty() {
+        println!("  No allocations attributed to this file.");
+    } else if !inclusive {
+        let (count, bytes) = lines
+            .values()
+            .fold((0, 0), |(c, b), (count, bytes)| (c + count, b + bytes));
+        println!(
+            "{:>8} {:>11} │ total",
+            count,
+            format!("{:.2} KB", bytes as f64 / 1024.0)
+        );
+    }
+
+    Ok(())
+}
+
+/// Whether a recorded frame's file is the annotated source file. Recorded
+/// paths are absolute, so fall back to a suffix match for profiles taken on
+/// another machine or checkout.
+fn same_file(frame_file: &Path, source_path: &Path, canonical: Option<&PathBuf>) -> bool {
+    if let (Some(canonical), Ok(frame_canonical)) = (canonical, frame_file.canonicalize()) {
+        return *canonical == frame_canonical;
+    }
+    let relative: PathBuf = source_path
+        .components()
+        .filter(|c| matches!(c, std::path::Component::Normal(_)))
+        .collect();
+    frame_file.ends_with(relative)
+}
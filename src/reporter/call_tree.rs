@@ -0,0 +1,200 @@
+//! Call trees merged from every allocation site's stack.
+
+use super::{ReportOptions, Reporter, SortBy, View};
+use crate::profiler::AllocationSite;
+use colored::*;
+use serde_json::json;
+use std::collections::HashMap;
+
+/// One function in the merged tree, with inclusive and exclusive totals
+#[derive(Default)]
+pub(crate) struct Node {
+    name: String,
+    count: usize,
+    bytes: usize,
+    self_count: usize,
+    self_bytes: usize,
+    children: HashMap<String, Node>,
+}
+
+/// Merge all stacks into one tree. Top-down trees start at the outermost
+/// caller; inverted trees start at the function that allocated.
+pub(crate) fn build(sites: &[&AllocationSite], view: View) -> Node {
+    let mut root = Node {
+        name: "all allocations".to_string(),
+        ..Node::default()
+    };
+
+    for site in sites {
+        let mut names: Vec<String> = site
+            .frames
+            .iter()
+            .map(|frame| Reporter::split_frame(frame).0.to_string())
+            .collect();
+        if view != View::Inverted {
+            names.reverse();
+        }
+        // Index of the allocating frame along the path
+        let leaf = match view {
+            View::Inverted => 0,
+            View::Flat | View::Tree => names.len().saturating_sub(1),
+        };
+
+        root.count += site.count;
+        root.bytes += site.total_bytes;
+        let mut node = &mut root;
+        for (depth, name) in names.into_iter().enumerate() {
+            node = node.children.entry(name.clone()).or_insert_with(|| Node {
+                name,
+                ..Node::default()
+            });
+            node.count += site.count;
+            node.bytes += site.total_bytes;
+            if depth == leaf {
+                node.self_count += site.count;
+                node.self_bytes += site.total_bytes;
+            }
+        }
+    }
+
+    root
+}
+
+/// Print the tree with box-drawing guides, hiding subtrees below the prune
+/// percentage
+pub(crate) fn print(root: &Node, options: &ReportOptions) {
+    let title = match options.view {
+        View::Inverted => "Inverted Call Tree:",
+        View::Flat | View::Tree => "Allocation Call Tree:",
+    };
+    println!("\n{}", title.bright_blue().bold());
+
+    if root.count == 0 {
+        println!("  No allocations recorded.");
+        return;
+    }
+
+    println!("{}", describe(root, root, options));
+    print_children(root, root, "", options);
+}
+
+fn print_children(node: &Node, root: &Node, prefix: &str, options: &ReportOptions) {
+    let children = visible_children(node, root, options);
+    let last = children.len().saturating_sub(1);
+    for (i, child) in children.into_iter().enumerate() {
+        let (branch, guide) = if i == last {
+            ("└─ ", "   ")
+        } else {
+            ("├─ ", "│  ")
+        };
+        println!(
+            "{}{}",
+            format!("{}{}", prefix, branch).dimmed(),
+            describe(child, root, options)
+        );
+        print_children(child, root, &format!("{}{}", prefix, guide), options);
+    }
+}
+
+fn describe(node: &Node, root: &Node, options: &ReportOptions) -> String {
+    let mut line = format!(
+        "{} {} {} ({:.2} KB)",
+        format!("{:5.1}%", percent(node, root, options)).bright_cyan(),
+        node.name.bright_white(),
+        node.count.to_string().bright_green(),
+        node.bytes as f64 / 1024.0
+    );
+    if node.self_count > 0 {
+        line.push_str(&format!(
+            " {}",
+            format!(
+                "[self: {} ({:.2} KB)]",
+                node.self_count,
+                node.self_bytes as f64 / 1024.0
+            )
+            .dimmed()
+        ));
+    }
+    line
+}
+
+/// Share of the whole profile, by bytes when sorting by size and by count
+/// otherwise
+fn percent(node: &Node, root: &Node, options: &ReportOptions) -> f64 {
+    let (part, total) = match options.sort_by {
+        SortBy::Size => (node.bytes, root.bytes),
+        SortBy::Count | SortBy::Name => (node.count, root.count),
+    };
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+fn visible_children<'a>(node: &'a Node, root: &Node, options: &ReportOptions) -> Vec<&'a Node> {
+    let mut children: Vec<&Node> = node
+        .children
+        .values()
+        .filter(|child| percent(child, root, options) >= options.prune_percent)
+        .collect();
+    match options.sort_by {
+        SortBy::Count => children.sort_by(|a, b| b.count.cmp(&a.count).then(a.name.cmp(&b.name))),
+        SortBy::Size => children.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.name.cmp(&b.name))),
+        SortBy::Name => children.sort_by(|a, b| a.name.cmp(&b.name)),
+    }
+    if let Some(limit) = options.limit {
+        children.truncate(limit);
+    }
+    children
+}
+
+/// The tree as nested JSON objects, pruned like the text output
+pub(crate) fn to_json(node: &Node, root: &Node, options: &ReportOptions) -> serde_json::Value {
+    let children: Vec<_> = visible_children(node, root, options)
+        .into_iter()
+        .map(|child| to_json(child, root, options))
+        .collect();
+    json!({
+        "function": node.name,
+        "count": node.count,
+        "total_bytes": node.bytes,
+        "self_count": node.self_count,
+        "self_bytes": node.self_bytes,
+        "percent": percent(node, root, options),
+        "children": children,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_with_parentheses_stay_apart() {
+        let site = |frames: &[&str]| -> AllocationSite {
+            serde_json::from_value(json!({
+                "count": 1,
+                "total_bytes": 8,
+                "frames": frames,
+            }))
+            .unwrap()
+        };
+        let closure = "<&dyn core::ops::function::Fn<(), Output = i32>>::call_once (src/lib.rs:3)";
+        let sites = [
+            site(&[closure, "app::main (src/main.rs:5)"]),
+            site(&[
+                "<&dyn core::ops::function::Fn<(), Output = u8>>::call_once (src/lib.rs:9)",
+                "app::main (src/main.rs:6)",
+            ]),
+        ];
+        let root = build(&sites.iter().collect::<Vec<_>>(), View::Tree);
+
+        let main = &root.children["app::main"];
+        assert_eq!(main.children.len(), 2);
+        assert!(
+            main.children
+                .contains_key("<&dyn core::ops::function::Fn<(), Output = i32>>::call_once")
+        );
+    }
+}
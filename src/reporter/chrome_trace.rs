@@ -0,0 +1,70 @@
+//! Writer for the Chrome Trace Event JSON format, as opened by Perfetto UI
+//! and `chrome://tracing`.
+//!
+//! See <https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU>.
+
+use super::Reporter;
+use crate::profiler::ProfileSnapshot;
+use serde_json::json;
+
+/// Render the snapshot's memory timeline, large allocations and threads as
+/// Chrome trace events
+pub(crate) fn render(snapshot: &ProfileSnapshot) -> std::io::Result<String> {
+    let pid = snapshot.pid;
+    // Timestamps are wall-clock microseconds, so traces from other tools and
+    // processes line up with this one
+    let epoch = snapshot.start_time_us;
+    let mut events = vec![json!({
+        "name": "process_name",
+        "ph": "M",
+        "pid": pid,
+        "args": { "name": "cargo-alloc-profile" },
+    })];
+
+    let mut threads: Vec<_> = snapshot.threads.iter().collect();
+    threads.sort();
+    for (tid, name) in threads {
+        events.push(json!({
+            "name": "thread_name",
+            "ph": "M",
+            "pid": pid,
+            "tid": tid,
+            "args": { "name": name },
+        }));
+    }
+
+    for sample in &snapshot.timeline {
+        events.push(json!({
+            "name": "Heap",
+            "ph": "C",
+            "ts": epoch + sample.time_us,
+            "pid": pid,
+            "args": { "current_memory": sample.current_memory },
+        }));
+    }
+
+    for allocation in &snapshot.large_allocations {
+        let stack: Vec<String> = allocation
+            .frames
+            .iter()
+            .map(|frame| Reporter::restore_generics(frame))
+            .collect();
+        events.push(json!({
+            "name": format!("Large allocation ({:.2} KB)", allocation.size as f64 / 1024.0),
+            "cat": "allocation",
+            "ph": "i",
+            "s": "t",
+            "ts": epoch + allocation.time_us,
+            "pid": pid,
+            "tid": allocation.thread,
+            "args": { "size": allocation.size, "stack": stack },
+        }));
+    }
+
+    let trace = json!({
+        "traceEvents": events,
+        "displayTimeUnit": "ms",
+    });
+
+    Ok(serde_json::to_string(&trace)?)
+}
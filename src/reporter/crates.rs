@@ -0,0 +1,221 @@
+//! Maps the source paths in stack frames to the crates that own them, using
+//! `cargo metadata` for the current project and path conventions for the
+//! standard library and cargo's registry and git caches.
+
+use super::Reporter;
+use once_cell::sync::Lazy;
+use std::path::{Path, PathBuf};
+
+/// Crates of the project in the current directory, loaded on first use
+pub(crate) static CRATES: Lazy<CrateMap> = Lazy::new(CrateMap::load);
+
+// Crates that ship with the toolchain
+const STD_CRATES: &[&str] = &[
+    "std",
+    "core",
+    "alloc",
+    "proc_macro",
+    "test",
+    "panic_unwind",
+    "panic_abort",
+    "__rustc",
+];
+
+/// The crate a frame belongs to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CrateId {
+    pub name: String,
+    pub version: Option<String>,
+    pub is_std: bool,
+}
+
+impl std::fmt::Display for CrateId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.version {
+            Some(ref version) => write!(f, "{} v{}", self.name, version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+struct Package {
+    name: String,
+    version: String,
+    dir: PathBuf,
+    workspace_member: bool,
+}
+
+#[derive(Default)]
+pub(crate) struct CrateMap {
+    // Longest directories first, so nested packages win over their parents
+    packages: Vec<Package>,
+    target_dir: Option<PathBuf>,
+}
+
+impl CrateMap {
+    fn load() -> Self {
+        let output = std::process::Command::new("cargo")
+            .args(["metadata", "--format-version", "1"])
+            .stderr(std::process::Stdio::null())
+            .output();
+        match output {
+            Ok(output) if output.status.success() => serde_json::from_slice(&output.stdout)
+                .map(|metadata| Self::from_metadata(&metadata))
+                .unwrap_or_default(),
+            _ => Self::default(),
+        }
+    }
+
+    fn from_metadata(metadata: &serde_json::Value) -> Self {
+        let members: Vec<&str> = metadata["workspace_members"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|id| id.as_str())
+            .collect();
+        let mut packages: Vec<Package> = metadata["packages"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|package| {
+                let manifest = Path::new(package["manifest_path"].as_str()?);
+                Some(Package {
+                    name: package["name"].as_str()?.to_string(),
+                    version: package["version"].as_str()?.to_string(),
+                    dir: manifest.parent()?.to_path_buf(),
+                    workspace_member: members.contains(&package["id"].as_str()?),
+                })
+            })
+            .collect();
+        packages.sort_by_key(|package| std::cmp::Reverse(package.dir.as_os_str().len()));
+        Self {
+            packages,
+            target_dir: metadata["target_directory"].as_str().map(PathBuf::from),
+        }
+    }
+
+    fn package_of(&self, file: &str) -> Option<&Package> {
+        self.packages
+            .iter()
+            .find(|package| Path::new(file).starts_with(&package.dir))
+    }
+
+    /// Whether the frame's source file belongs to a workspace member, not
+    /// counting generated code under the target directory or the allocator
+    /// shim, which takes the location of `#[global_allocator]`
+    pub(crate) fn is_workspace_frame(&self, frame: &str) -> bool {
+        let (function, Some(file), _) = Reporter::split_frame(frame) else {
+            return false;
+        };
+        if function.starts_with("__rust") {
+            return false;
+        }
+        if let Some(ref target_dir) = self.target_dir
+            && Path::new(file).starts_with(target_dir)
+        {
+            return false;
+        }
+        self.package_of(file)
+            .is_some_and(|package| package.workspace_member)
+    }
+
+    /// The crate owning a frame, by source path when it has one and by the
+    /// first segment of its symbol path otherwise
+    pub(crate) fn crate_of(&self, frame: &str) -> CrateId {
+        let (_, file, _) = Reporter::split_frame(frame);
+        if let Some(file) = file {
+            if let Some(package) = self.package_of(file) {
+                return CrateId {
+                    name: package.name.clone(),
+                    version: Some(package.version.clone()),
+                    is_std: false,
+                };
+            }
+            if let Some(id) = crate_from_path(file) {
+                return id;
+            }
+        }
+
+        let name = symbol_crate(frame);
+        CrateId {
+            is_std: STD_CRATES.contains(&name.as_str()),
+            name,
+            version: None,
+        }
+    }
+
+    /// The first crate along the stack that is not part of the toolchain,
+    /// falling back to the crate of the allocating frame
+    pub(crate) fn attribute(&self, frames: &[String]) -> Option<CrateId> {
+        let crates: Vec<CrateId> = frames.iter().map(|frame| self.crate_of(frame)).collect();
+        crates
+            .iter()
+            .find(|id| !id.is_std)
+            .or(crates.first())
+            .cloned()
+    }
+}
+
+// Crates recognized from where their sources live: the toolchain's library
+// directory, the registry of the machine that built std, and cargo's registry
+// and git caches
+fn crate_from_path(file: &str) -> Option<CrateId> {
+    let file = file.replace('\\', "/");
+
+    if file.starts_with("/rustc/") || file.contains("/rustlib/src/rust/") {
+        let rest = &file[file.find("/library/")? + "/library/".len()..];
+        let name = rest.split('/').next()?;
+        return Some(CrateId {
+            name: name.to_string(),
+            version: None,
+            is_std: true,
+        });
+    }
+
+    let (rest, is_std) = if let Some(rest) = file.strip_prefix("/cargo/registry/src/") {
+        (rest, true)
+    } else if let Some(pos) = file.find("/registry/src/") {
+        (&file[pos + "/registry/src/".len()..], false)
+    } else if let Some(pos) = file.find("/git/checkouts/") {
+        // <repository>-<hash>/<revision>/...
+        let repository = file[pos + "/git/checkouts/".len()..].split('/').next()?;
+        let name = repository
+            .rsplit_once('-')
+            .map_or(repository, |(name, _)| name);
+        return Some(CrateId {
+            name: name.to_string(),
+            version: None,
+            is_std: false,
+        });
+    } else {
+        return None;
+    };
+
+    // <index>/<name>-<version>/...
+    let dir = rest.split('/').nth(1)?;
+    let split = dir
+        .match_indices('-')
+        .map(|(i, _)| i)
+        .find(|&i| dir[i + 1..].starts_with(|c: char| c.is_ascii_digit()))?;
+    Some(CrateId {
+        name: dir[..split].to_string(),
+        version: Some(dir[split + 1..].to_string()),
+        is_std,
+    })
+}
+
+// First segment of the frame's symbol path, without generics or v0 hashes
+fn symbol_crate(frame: &str) -> String {
+    let name = Reporter::split_frame(frame).0;
+    let name = name.trim_start_matches(['‹', '<', '&']);
+    let segment = name.split("::").next().unwrap_or(name);
+    let segment = segment
+        .split(['[', '‹', '<', ' '])
+        .next()
+        .unwrap_or(segment);
+    if segment.is_empty() {
+        "unknown".to_string()
+    } else {
+        segment.to_string()
+    }
+}
@@ -0,0 +1,74 @@
+//! Writer for DHAT's JSON format, as loaded by Valgrind's `dh_view.html`.
+//!
+//! The schema is documented in Valgrind's `dhat/dh_main.c`. Access counts
+//! are not recorded, so the output declares `bkacc: false` like dhat-rs does.
+
+use super::Reporter;
+use crate::profiler::{AllocationSite, ProfileSnapshot};
+use serde_json::json;
+use std::collections::HashMap;
+
+/// Render the given allocation sites as DHAT program points with a shared
+/// frame table
+pub(crate) fn render(
+    snapshot: &ProfileSnapshot,
+    sites: &[&AllocationSite],
+) -> std::io::Result<String> {
+    // Index 0 is reserved for the root node in dh_view
+    let mut frame_table = vec!["[root]".to_string()];
+    let mut frame_ids: HashMap<&str, usize> = HashMap::new();
+
+    let program_points: Vec<_> = sites
+        .iter()
+        .map(|site| {
+            let frames: Vec<usize> = site
+                .frames
+                .iter()
+                .map(|frame| {
+                    *frame_ids.entry(frame.as_str()).or_insert_with(|| {
+                        let id = frame_table.len();
+                        frame_table.push(format!(
+                            "0x{:X}: {}",
+                            id,
+                            Reporter::restore_generics(frame)
+                        ));
+                        id
+                    })
+                })
+                .collect();
+
+            json!({
+                "tb": site.total_bytes,
+                "tbk": site.count,
+                "tl": site.total_lifetime_us,
+                "mb": site.max_live_bytes,
+                "mbk": site.max_live_count,
+                "gb": site.bytes_at_peak,
+                "gbk": site.count_at_peak,
+                "eb": site.live_bytes,
+                "ebk": site.live_count,
+                "fs": frames,
+            })
+        })
+        .collect();
+
+    let end_us = snapshot.timeline.last().map_or(0, |sample| sample.time_us);
+    let document = json!({
+        "dhatFileVersion": 2,
+        "mode": "rust-heap",
+        "verb": "Allocated",
+        "bklt": true,
+        "bkacc": false,
+        "tu": "µs",
+        "Mtu": "s",
+        "tuth": 10,
+        "cmd": snapshot.command,
+        "pid": snapshot.pid,
+        "tg": snapshot.peak_time_us,
+        "te": end_us,
+        "pps": program_points,
+        "ftbl": frame_table,
+    });
+
+    Ok(serde_json::to_string(&document)?)
+}
@@ -0,0 +1,299 @@
+//! Markdown report for pasting into GitHub or GitLab comments.
+
+use super::{ReportOptions, SiteComparison};
+use crate::profiler::ProfileSnapshot;
+use std::fmt::Write;
+
+/// Number of sites shown when `--limit` is not given, to keep comments short
+const DEFAULT_ROWS: usize = 20;
+
+type Site = (String, usize, usize, Vec<String>);
+
+/// Render the summary and top allocation sites
+pub(crate) fn render(
+    snapshot: &ProfileSnapshot,
+    sites: &[Site],
+    options: &ReportOptions,
+) -> String {
+    let mut out = String::new();
+    writeln!(out, "## Allocation Profile\n").unwrap();
+
+    writeln!(out, "| Metric | Value |").unwrap();
+    writeln!(out, "| --- | ---: |").unwrap();
+    for (metric, value) in summary_rows(snapshot) {
+        writeln!(out, "| {} | {} |", metric, value).unwrap();
+    }
+
+    let rows = options.limit.unwrap_or(DEFAULT_ROWS);
+    writeln!(out, "\n### Top allocation sites\n").unwrap();
+    if sites.is_empty() {
+        writeln!(out, "No allocations recorded.").unwrap();
+        return out;
+    }
+
+    writeln!(out, "| # | Site | Count | Bytes |").unwrap();
+    writeln!(out, "| ---: | --- | ---: | ---: |").unwrap();
+    for (rank, (name, count, bytes, _)) in sites.iter().take(rows).enumerate() {
+        writeln!(
+            out,
+            "| {} | {} | {} | {} |",
+            rank + 1,
+            code(name),
+            count,
+            format_bytes(*bytes as isize)
+        )
+        .unwrap();
+    }
+    write_remaining(&mut out, sites.len(), rows);
+
+    write_stack_traces(
+        &mut out,
+        sites
+            .iter()
+            .take(rows)
+            .map(|(name, _, _, frames)| (name.as_str(), frames.as_slice())),
+    );
+    out
+}
+
+/// Render the summary and per-site deltas against a baseline
+pub(crate) fn render_comparison(
+    snapshot: &ProfileSnapshot,
+    baseline: &ProfileSnapshot,
+    comparisons: &[SiteComparison],
+    options: &ReportOptions,
+) -> String {
+    let mut out = String::new();
+    writeln!(out, "## Allocation Comparison\n").unwrap();
+
+    writeln!(out, "| Metric | Baseline | Current | Change |").unwrap();
+    writeln!(out, "| --- | ---: | ---: | ---: |").unwrap();
+    let metrics = [
+        (
+            "Total allocations",
+            baseline.total_allocations,
+            snapshot.total_allocations,
+            false,
+        ),
+        (
+            "Total deallocations",
+            baseline.total_deallocations,
+            snapshot.total_deallocations,
+            false,
+        ),
+        (
+            "Bytes allocated",
+            baseline.total_bytes_allocated,
+            snapshot.total_bytes_allocated,
+            true,
+        ),
+        (
+            "Peak memory",
+            baseline.peak_memory,
+            snapshot.peak_memory,
+            true,
+        ),
+        (
+            "Memory at exit",
+            baseline.current_memory,
+            snapshot.current_memory,
+            true,
+        ),
+    ];
+    for (metric, before, after, is_bytes) in metrics {
+        let show = |value: usize| {
+            if is_bytes {
+                format_bytes(value as isize)
+            } else {
+                value.to_string()
+            }
+        };
+        let diff = after as isize - before as isize;
+        let change = if is_bytes {
+            signed_bytes(diff)
+        } else {
+            signed_count(diff)
+        };
+        writeln!(
+            out,
+            "| {} | {} | {} | {} {} |",
+            metric,
+            show(before),
+            show(after),
+            arrow(diff),
+            change
+        )
+        .unwrap();
+    }
+
+    let rows = options.limit.unwrap_or(DEFAULT_ROWS);
+    writeln!(out, "\n### Allocation site changes\n").unwrap();
+    if comparisons.is_empty() {
+        writeln!(out, "No allocations recorded.").unwrap();
+        return out;
+    }
+
+    writeln!(out, "| | Site | Count | Bytes |").unwrap();
+    writeln!(out, "| :---: | --- | ---: | ---: |").unwrap();
+    for comparison in comparisons.iter().take(rows) {
+        let name = code(&comparison.name);
+        match (comparison.baseline, comparison.current) {
+            (Some((base_count, base_bytes)), Some((count, bytes))) => {
+                let count_diff = count as isize - base_count as isize;
+                let bytes_diff = bytes as isize - base_bytes as isize;
+                let status = match (bytes_diff.signum(), count_diff.signum()) {
+                    (1, _) | (0, 1) => "🔺",
+                    (-1, _) | (0, -1) => "✅",
+                    _ => "➖",
+                };
+                writeln!(
+                    out,
+                    "| {} | {} | {} → {} ({} {}) | {} → {} ({} {}) |",
+                    status,
+                    name,
+                    base_count,
+                    count,
+                    arrow(count_diff),
+                    signed_count(count_diff),
+                    format_bytes(base_bytes as isize),
+                    format_bytes(bytes as isize),
+                    arrow(bytes_diff),
+                    signed_bytes(bytes_diff)
+                )
+                .unwrap();
+            }
+            (None, Some((count, bytes))) => {
+                writeln!(
+                    out,
+                    "| 🆕 | {} | {} | {} |",
+                    name,
+                    count,
+                    format_bytes(bytes as isize)
+                )
+                .unwrap();
+            }
+            (Some((count, bytes)), None) => {
+                writeln!(
+                    out,
+                    "| 🗑️ | ~~{}~~ | ~~{}~~ | ~~{}~~ |",
+                    name,
+                    count,
+                    format_bytes(bytes as isize)
+                )
+                .unwrap();
+            }
+            (None, None) => {}
+        }
+    }
+    write_remaining(&mut out, comparisons.len(), rows);
+    writeln!(
+        out,
+        "\n🔺 more allocations · ✅ fewer allocations · 🆕 new site · 🗑️ removed site"
+    )
+    .unwrap();
+
+    write_stack_traces(
+        &mut out,
+        comparisons
+            .iter()
+            .take(rows)
+            .map(|comparison| (comparison.name.as_str(), comparison.frames.as_slice())),
+    );
+    out
+}
+
+fn summary_rows(snapshot: &ProfileSnapshot) -> [(&'static str, String); 5] {
+    [
+        ("Total allocations", snapshot.total_allocations.to_string()),
+        (
+            "Total deallocations",
+            snapshot.total_deallocations.to_string(),
+        ),
+        (
+            "Bytes allocated",
+            format_bytes(snapshot.total_bytes_allocated as isize),
+        ),
+        ("Peak memory", format_bytes(snapshot.peak_memory as isize)),
+        (
+            "Memory at exit",
+            format_bytes(snapshot.current_memory as isize),
+        ),
+    ]
+}
+
+fn write_remaining(out: &mut String, total: usize, shown: usize) {
+    if total > shown {
+        writeln!(out, "\n_… and {} more sites_", total - shown).unwrap();
+    }
+}
+
+fn write_stack_traces<'a>(out: &mut String, sites: impl Iterator<Item = (&'a str, &'a [String])>) {
+    writeln!(out, "\n### Stack traces\n").unwrap();
+    for (name, frames) in sites {
+        if frames.is_empty() {
+            continue;
+        }
+        writeln!(out, "<details>").unwrap();
+        writeln!(out, "<summary>{}</summary>\n", html_code(name)).unwrap();
+        writeln!(out, "```text").unwrap();
+        for frame in frames {
+            writeln!(out, "{}", frame).unwrap();
+        }
+        writeln!(out, "```\n").unwrap();
+        writeln!(out, "</details>\n").unwrap();
+    }
+}
+
+fn arrow(diff: isize) -> &'static str {
+    match diff.signum() {
+        1 => "↑",
+        -1 => "↓",
+        _ => "→",
+    }
+}
+
+fn signed_count(diff: isize) -> String {
+    if diff > 0 {
+        format!("+{}", diff)
+    } else if diff < 0 {
+        diff.to_string()
+    } else {
+        "±0".to_string()
+    }
+}
+
+fn signed_bytes(diff: isize) -> String {
+    if diff > 0 {
+        format!("+{}", format_bytes(diff))
+    } else if diff < 0 {
+        format!("-{}", format_bytes(-diff))
+    } else {
+        "±0 B".to_string()
+    }
+}
+
+fn format_bytes(bytes: isize) -> String {
+    let magnitude = bytes.unsigned_abs() as f64;
+    let sign = if bytes < 0 { "-" } else { "" };
+    if magnitude >= 1024.0 * 1024.0 {
+        format!("{}{:.2} MB", sign, magnitude / (1024.0 * 1024.0))
+    } else if magnitude >= 1024.0 {
+        format!("{}{:.2} KB", sign, magnitude / 1024.0)
+    } else {
+        format!("{}{} B", sign, magnitude)
+    }
+}
+
+/// Inline code for a table cell; pipes would otherwise end the cell
+fn code(name: &str) -> String {
+    format!("`{}`", name.replace('|', "\\|"))
+}
+
+/// Inline code inside raw HTML, where Markdown backticks are not rendered
+fn html_code(name: &str) -> String {
+    let escaped = name
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    format!("<code>{}</code>", escaped)
+}
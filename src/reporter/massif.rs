@@ -0,0 +1,166 @@
+//! Writer for Valgrind Massif's `massif.out` format, as read by `ms_print`
+//! and massif-visualizer.
+//!
+//! See <https://valgrind.org/docs/manual/ms-manual.html>.
+
+use super::Reporter;
+use crate::profiler::{AllocationSite, ProfileSnapshot};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+/// Upper bound on the number of periodic heap-total snapshots, like Massif's
+/// default `--max-snapshots`
+const MAX_SNAPSHOTS: usize = 100;
+
+/// Tree nodes below this share of the snapshot total are merged, like
+/// Massif's default `--threshold`
+const THRESHOLD_PERCENT: f64 = 1.0;
+
+enum HeapTree {
+    Empty,
+    Detailed(Node),
+    Peak(Node),
+}
+
+/// Render the snapshot's timeline as periodic heap totals, with detailed
+/// allocation trees at the peak and at exit
+pub(crate) fn render(
+    snapshot: &ProfileSnapshot,
+    sites: &[&AllocationSite],
+) -> std::io::Result<String> {
+    let mut snapshots: Vec<(u64, usize, HeapTree)> = Vec::new();
+
+    let step = snapshot.timeline.len().div_ceil(MAX_SNAPSHOTS).max(1);
+    for sample in snapshot.timeline.iter().step_by(step) {
+        snapshots.push((sample.time_us, sample.current_memory, HeapTree::Empty));
+    }
+
+    let peak = build_tree(sites, |site| site.bytes_at_peak);
+    if peak.bytes > 0 {
+        snapshots.push((snapshot.peak_time_us, peak.bytes, HeapTree::Peak(peak)));
+    }
+
+    let end_time = snapshot.timeline.last().map_or(0, |sample| sample.time_us);
+    let live = build_tree(sites, |site| site.live_bytes);
+    snapshots.push((end_time, live.bytes, HeapTree::Detailed(live)));
+
+    // The sort is stable, so the exit snapshot stays last
+    snapshots.sort_by_key(|(time_us, _, _)| *time_us);
+
+    let mut out = String::new();
+    let mut addresses = HashMap::new();
+    writeln!(out, "desc: cargo alloc-profile").unwrap();
+    writeln!(out, "cmd: {}", snapshot.command).unwrap();
+    writeln!(out, "time_unit: ms").unwrap();
+
+    for (index, (time_us, heap_bytes, tree)) in snapshots.iter().enumerate() {
+        writeln!(out, "#-----------").unwrap();
+        writeln!(out, "snapshot={}", index).unwrap();
+        writeln!(out, "#-----------").unwrap();
+        writeln!(out, "time={}", time_us / 1000).unwrap();
+        writeln!(out, "mem_heap_B={}", heap_bytes).unwrap();
+        writeln!(out, "mem_heap_extra_B=0").unwrap();
+        writeln!(out, "mem_stacks_B=0").unwrap();
+
+        match tree {
+            HeapTree::Empty => writeln!(out, "heap_tree=empty").unwrap(),
+            HeapTree::Detailed(root) | HeapTree::Peak(root) => {
+                let kind = if matches!(tree, HeapTree::Peak(_)) {
+                    "peak"
+                } else {
+                    "detailed"
+                };
+                writeln!(out, "heap_tree={}", kind).unwrap();
+                let threshold = (root.bytes as f64 * THRESHOLD_PERCENT / 100.0) as usize;
+                write_node(
+                    &mut out,
+                    "(heap allocation functions) malloc/new/new[], --alloc-fns, etc.",
+                    root,
+                    0,
+                    threshold,
+                    &mut addresses,
+                );
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// A node in the allocation tree, rooted at the allocation functions and
+/// growing outwards through their callers
+#[derive(Default)]
+struct Node {
+    bytes: usize,
+    children: BTreeMap<String, Node>,
+}
+
+fn build_tree(sites: &[&AllocationSite], bytes_of: impl Fn(&AllocationSite) -> usize) -> Node {
+    let mut root = Node::default();
+    for site in sites {
+        let bytes = bytes_of(site);
+        if bytes == 0 {
+            continue;
+        }
+
+        root.bytes += bytes;
+        let mut node = &mut root;
+        for frame in &site.frames {
+            node = node.children.entry(frame.clone()).or_default();
+            node.bytes += bytes;
+        }
+    }
+    root
+}
+
+fn write_node(
+    out: &mut String,
+    description: &str,
+    node: &Node,
+    depth: usize,
+    threshold: usize,
+    addresses: &mut HashMap<String, usize>,
+) {
+    let mut children: Vec<_> = node.children.iter().collect();
+    children.sort_by_key(|(_, child)| std::cmp::Reverse(child.bytes));
+    let (significant, below): (Vec<_>, Vec<_>) = children
+        .into_iter()
+        .partition(|(_, child)| child.bytes >= threshold.max(1));
+
+    let child_count = significant.len() + usize::from(!below.is_empty());
+    writeln!(
+        out,
+        "{:indent$}n{}: {} {}",
+        "",
+        child_count,
+        node.bytes,
+        description,
+        indent = depth
+    )
+    .unwrap();
+
+    for (frame, child) in significant {
+        // Massif descriptions start with a code address; frames are already
+        // symbolized, so each distinct frame just gets a stable fake one
+        let next = addresses.len() + 1;
+        let address = *addresses.entry(frame.clone()).or_insert(next);
+        let description = format!("0x{:X}: {}", address, Reporter::restore_generics(frame));
+        write_node(out, &description, child, depth + 1, threshold, addresses);
+    }
+
+    if !below.is_empty() {
+        let bytes: usize = below.iter().map(|(_, child)| child.bytes).sum();
+        let places = below.len();
+        writeln!(
+            out,
+            "{:indent$}n0: {} in {} place{}, below massif's threshold ({:.2}%)",
+            "",
+            bytes,
+            places,
+            if places == 1 { "" } else { "s" },
+            THRESHOLD_PERCENT,
+            indent = depth + 1
+        )
+        .unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+//! Encoder for pprof's `profile.proto` heap profile format.
+//!
+//! The protobuf messages are small and fixed, so they are written by hand
+//! rather than pulling in a code generator. Field numbers follow
+//! <https://github.com/google/pprof/blob/main/proto/profile.proto>.
+
+use super::Reporter;
+use crate::profiler::AllocationSite;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Encode the given allocation sites as a gzipped pprof profile
+pub(crate) fn encode(sites: &[&AllocationSite]) -> std::io::Result<Vec<u8>> {
+    let mut builder = ProfileBuilder::default();
+    // String index 0 must always be the empty string
+    builder.string("");
+
+    // Live data is only available for allocations tracked by address, which
+    // every site has had at least one of; profiles saved before live
+    // tracking have none, and get no `inuse_*` sample types
+    let live = sites.iter().any(|site| site.max_live_count > 0);
+    let sample_types = [
+        ("alloc_objects", "count"),
+        ("alloc_space", "bytes"),
+        ("inuse_objects", "count"),
+        ("inuse_space", "bytes"),
+    ];
+    let sample_types = if live {
+        &sample_types[..]
+    } else {
+        &sample_types[..2]
+    };
+
+    let mut profile = Vec::new();
+    for &(kind, unit) in sample_types {
+        let value_type = builder.value_type(kind, unit);
+        write_bytes_field(&mut profile, 1, &value_type);
+    }
+
+    for site in sites {
+        let location_ids: Vec<u64> = site
+            .frames
+            .iter()
+            .map(|frame| builder.location(frame))
+            .collect();
+        let values = [
+            site.count as u64,
+            site.total_bytes as u64,
+            site.live_count as u64,
+            site.live_bytes as u64,
+        ];
+        let values = &values[..sample_types.len()];
+
+        let mut sample = Vec::new();
+        write_packed_field(&mut sample, 1, &location_ids);
+        write_packed_field(&mut sample, 2, values);
+        write_bytes_field(&mut profile, 2, &sample);
+    }
+
+    // A single mapping that tells viewers the locations are already symbolized
+    let mut mapping = Vec::new();
+    write_varint_field(&mut mapping, 1, 1);
+    write_varint_field(&mut mapping, 7, 1);
+    write_varint_field(&mut mapping, 8, 1);
+    write_varint_field(&mut mapping, 9, 1);
+    write_bytes_field(&mut profile, 3, &mapping);
+
+    let period_type = builder.value_type("space", "bytes");
+    let default_sample_type = builder.string("alloc_space");
+    let time_nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+
+    for location in &builder.locations {
+        write_bytes_field(&mut profile, 4, location);
+    }
+    for function in &builder.functions {
+        write_bytes_field(&mut profile, 5, function);
+    }
+    for string in &builder.strings {
+        write_bytes_field(&mut profile, 6, string.as_bytes());
+    }
+    write_varint_field(&mut profile, 9, time_nanos);
+    write_bytes_field(&mut profile, 11, &period_type);
+    write_varint_field(&mut profile, 12, 1);
+    write_varint_field(&mut profile, 14, default_sample_type);
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&profile)?;
+    encoder.finish()
+}
+
+/// Deduplicated string, function and location tables
+#[derive(Default)]
+struct ProfileBuilder {
+    strings: Vec<String>,
+    string_ids: HashMap<String, u64>,
+    functions: Vec<Vec<u8>>,
+    function_ids: HashMap<(u64, u64), u64>,
+    locations: Vec<Vec<u8>>,
+    location_ids: HashMap<String, u64>,
+}
+
+impl ProfileBuilder {
+    fn string(&mut self, value: &str) -> u64 {
+        if let Some(&id) = self.string_ids.get(value) {
+            return id;
+        }
+        let id = self.strings.len() as u64;
+        self.strings.push(value.to_string());
+        self.string_ids.insert(value.to_string(), id);
+        id
+    }
+
+    fn value_type(&mut self, kind: &str, unit: &str) -> Vec<u8> {
+        let mut message = Vec::new();
+        write_varint_field(&mut message, 1, self.string(kind));
+        write_varint_field(&mut message, 2, self.string(unit));
+        message
+    }
+
+    fn function(&mut self, name: &str, file: Option<&str>) -> u64 {
+        let name = self.string(&Reporter::restore_generics(name));
+        let file = file.map(|f| self.string(f)).unwrap_or(0);
+        if let Some(&id) = self.function_ids.get(&(name, file)) {
+            return id;
+        }
+
+        let id = self.functions.len() as u64 + 1;
+        let mut message = Vec::new();
+        write_varint_field(&mut message, 1, id);
+        write_varint_field(&mut message, 2, name);
+        write_varint_field(&mut message, 3, name);
+        write_varint_field(&mut message, 4, file);
+        self.functions.push(message);
+        self.function_ids.insert((name, file), id);
+        id
+    }
+
+    fn location(&mut self, frame: &str) -> u64 {
+        if let Some(&id) = self.location_ids.get(frame) {
+            return id;
+        }
+
+        let (name, file, line) = Reporter::split_frame(frame);
+        let function_id = self.function(name, file);
+
+        let mut line_message = Vec::new();
+        write_varint_field(&mut line_message, 1, function_id);
+        write_varint_field(&mut line_message, 2, line.unwrap_or(0) as u64);
+
+        let id = self.locations.len() as u64 + 1;
+        let mut message = Vec::new();
+        write_varint_field(&mut message, 1, id);
+        write_varint_field(&mut message, 2, 1);
+        write_bytes_field(&mut message, 4, &line_message);
+        self.locations.push(message);
+        self.location_ids.insert(frame.to_string(), id);
+        id
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn write_varint_field(buf: &mut Vec<u8>, field: u32, value: u64) {
+    // Zero is the protobuf default and is omitted on the wire
+    if value == 0 {
+        return;
+    }
+    write_varint(buf, u64::from(field) << 3);
+    write_varint(buf, value);
+}
+
+fn write_bytes_field(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    write_varint(buf, (u64::from(field) << 3) | 2);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_packed_field(buf: &mut Vec<u8>, field: u32, values: &[u64]) {
+    let mut packed = Vec::new();
+    for &value in values {
+        write_varint(&mut packed, value);
+    }
+    write_bytes_field(buf, field, &packed);
+}
@@ -0,0 +1,229 @@
+//! Writer for SARIF 2.1.0 logs, as consumed by CI code scanning and IDE
+//! SARIF viewers.
+//!
+//! See <https://docs.oasis-open.org/sarif/sarif/v2.1.0/sarif-v2.1.0.html>.
+
+use super::crates::CRATES;
+use super::{ReportOptions, Reporter, SortBy};
+use crate::profiler::{AllocationSite, ProfileSnapshot};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::Path;
+
+const HOTSPOT_RULE: &str = "allocation-hotspot";
+const REGRESSION_RULE: &str = "allocation-regression";
+
+/// Sites aggregated by the source line they are anchored to
+struct Anchored {
+    frame: String,
+    count: usize,
+    bytes: usize,
+    frames: Vec<String>,
+}
+
+/// Render one result per hot site, or per regressed site when a baseline is
+/// given, anchored to the first frame in the profiled project
+pub(crate) fn render(
+    sites: &[&AllocationSite],
+    baseline: Option<&ProfileSnapshot>,
+    options: &ReportOptions,
+) -> std::io::Result<String> {
+    let root = std::env::current_dir()?;
+    let mut current = anchor_sites(sites.iter().copied());
+    match options.sort_by {
+        SortBy::Count => current.sort_by_key(|site| std::cmp::Reverse(site.count)),
+        SortBy::Size => current.sort_by_key(|site| std::cmp::Reverse(site.bytes)),
+        SortBy::Name => current.sort_by(|a, b| a.frame.cmp(&b.frame)),
+    }
+
+    let mut results = Vec::new();
+    match baseline {
+        None => {
+            for site in &current {
+                let message = format!(
+                    "{} allocations ({:.2} KB) at {}",
+                    site.count,
+                    site.bytes as f64 / 1024.0,
+                    function_name(&site.frame)
+                );
+                results.push(result(HOTSPOT_RULE, "note", message, site, &root));
+            }
+        }
+        Some(baseline) => {
+            let before: HashMap<String, Anchored> =
+                anchor_sites(baseline.allocation_sites.values())
+                    .into_iter()
+                    .map(|site| (site.frame.clone(), site))
+                    .collect();
+
+            for site in &current {
+                let message = match before.get(&site.frame) {
+                    Some(old) if site.count > old.count || site.bytes > old.bytes => format!(
+                        "Allocations at {} increased from {} ({:.2} KB) to {} ({:.2} KB)",
+                        function_name(&site.frame),
+                        old.count,
+                        old.bytes as f64 / 1024.0,
+                        site.count,
+                        site.bytes as f64 / 1024.0
+                    ),
+                    Some(_) => continue,
+                    None => format!(
+                        "New allocation site at {}: {} allocations ({:.2} KB)",
+                        function_name(&site.frame),
+                        site.count,
+                        site.bytes as f64 / 1024.0
+                    ),
+                };
+                results.push(result(REGRESSION_RULE, "warning", message, site, &root));
+            }
+        }
+    }
+
+    if let Some(limit) = options.limit {
+        results.truncate(limit);
+    }
+
+    let log = json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "cargo-alloc-profile",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": "https://github.com/ciresnave/cargo-alloc-profile",
+                    "rules": [
+                        {
+                            "id": HOTSPOT_RULE,
+                            "name": "AllocationHotspot",
+                            "shortDescription": { "text": "Heap allocation hotspot" },
+                            "defaultConfiguration": { "level": "note" },
+                        },
+                        {
+                            "id": REGRESSION_RULE,
+                            "name": "AllocationRegression",
+                            "shortDescription": {
+                                "text": "More heap allocations than the baseline"
+                            },
+                            "defaultConfiguration": { "level": "warning" },
+                        },
+                    ],
+                },
+            },
+            "originalUriBaseIds": {
+                "SRCROOT": { "uri": directory_uri(&root) },
+            },
+            "results": results,
+        }],
+    });
+
+    Ok(serde_json::to_string_pretty(&log)?)
+}
+
+/// Aggregate sites by their first frame in a workspace member, skipping
+/// stacks that never enter the workspace
+fn anchor_sites<'a>(sites: impl Iterator<Item = &'a AllocationSite>) -> Vec<Anchored> {
+    let mut anchored: HashMap<String, Anchored> = HashMap::new();
+    for site in sites {
+        let Some(frame) = site
+            .frames
+            .iter()
+            .find(|frame| CRATES.is_workspace_frame(frame))
+        else {
+            continue;
+        };
+        anchored
+            .entry(frame.clone())
+            .and_modify(|entry| {
+                entry.count += site.count;
+                entry.bytes += site.total_bytes;
+            })
+            .or_insert_with(|| Anchored {
+                frame: frame.clone(),
+                count: site.count,
+                bytes: site.total_bytes,
+                frames: site.frames.clone(),
+            });
+    }
+    anchored.into_values().collect()
+}
+
+fn result(
+    rule: &str,
+    level: &str,
+    message: String,
+    site: &Anchored,
+    root: &Path,
+) -> serde_json::Value {
+    // Code flows run from the outermost caller down to the allocation
+    let flow: Vec<_> = site
+        .frames
+        .iter()
+        .rev()
+        .map(|frame| {
+            let mut location = json!({
+                "message": { "text": function_name(frame) },
+            });
+            if let Some(physical) = physical_location(frame, root) {
+                location["physicalLocation"] = physical;
+            }
+            json!({ "location": location })
+        })
+        .collect();
+
+    json!({
+        "ruleId": rule,
+        "level": level,
+        "message": { "text": message },
+        "locations": [{ "physicalLocation": physical_location(&site.frame, root) }],
+        "codeFlows": [{ "threadFlows": [{ "locations": flow }] }],
+        "properties": {
+            "count": site.count,
+            "bytes": site.bytes,
+        },
+    })
+}
+
+fn physical_location(frame: &str, root: &Path) -> Option<serde_json::Value> {
+    let (_, file, line) = Reporter::split_frame(frame);
+    let file = Path::new(file?);
+
+    let artifact = match file.strip_prefix(root) {
+        Ok(relative) => json!({ "uri": path_uri(relative), "uriBaseId": "SRCROOT" }),
+        Err(_) => json!({ "uri": file_uri(file) }),
+    };
+
+    let mut location = json!({ "artifactLocation": artifact });
+    if let Some(line) = line {
+        location["region"] = json!({ "startLine": line });
+    }
+    Some(location)
+}
+
+fn function_name(frame: &str) -> String {
+    Reporter::restore_generics(Reporter::split_frame(frame).0)
+}
+
+fn path_uri(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace('\\', "/")
+        .replace(' ', "%20")
+}
+
+fn file_uri(path: &Path) -> String {
+    let path = path_uri(path);
+    if path.starts_with('/') {
+        format!("file://{}", path)
+    } else {
+        format!("file:///{}", path)
+    }
+}
+
+fn directory_uri(path: &Path) -> String {
+    let uri = file_uri(path);
+    if uri.ends_with('/') {
+        uri
+    } else {
+        format!("{}/", uri)
+    }
+}
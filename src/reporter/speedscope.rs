@@ -0,0 +1,80 @@
+//! Writer for the speedscope file format.
+//!
+//! See <https://github.com/jlfwong/speedscope/wiki/Importing-from-custom-sources>
+//! and the schema at <https://www.speedscope.app/file-format-schema.json>.
+
+use super::Reporter;
+use crate::profiler::AllocationSite;
+use serde_json::json;
+use std::collections::HashMap;
+
+/// Render the given allocation sites as a speedscope JSON document with one
+/// profile weighted by bytes and one weighted by allocation count
+pub(crate) fn render(sites: &[&AllocationSite]) -> std::io::Result<String> {
+    let mut frames = Vec::new();
+    let mut frame_ids: HashMap<&str, usize> = HashMap::new();
+
+    // speedscope stacks are ordered from the root down to the leaf, while
+    // recorded frames start at the allocation point
+    let mut samples = Vec::with_capacity(sites.len());
+    for site in sites {
+        let stack: Vec<usize> = site
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                *frame_ids.entry(frame.as_str()).or_insert_with(|| {
+                    frames.push(frame_json(frame));
+                    frames.len() - 1
+                })
+            })
+            .collect();
+        samples.push(stack);
+    }
+
+    let bytes: Vec<usize> = sites.iter().map(|site| site.total_bytes).collect();
+    let counts: Vec<usize> = sites.iter().map(|site| site.count).collect();
+
+    let document = json!({
+        "$schema": "https://www.speedscope.app/file-format-schema.json",
+        "name": "Allocation profile",
+        "activeProfileIndex": 0,
+        "exporter": concat!("cargo-alloc-profile ", env!("CARGO_PKG_VERSION")),
+        "shared": { "frames": frames },
+        "profiles": [
+            sampled_profile("Allocated bytes", "bytes", &samples, &bytes),
+            sampled_profile("Allocation count", "none", &samples, &counts),
+        ],
+    });
+
+    Ok(serde_json::to_string(&document)?)
+}
+
+fn frame_json(frame: &str) -> serde_json::Value {
+    let (name, file, line) = Reporter::split_frame(frame);
+    let mut value = json!({ "name": Reporter::restore_generics(name) });
+    if let Some(file) = file {
+        value["file"] = json!(file);
+    }
+    if let Some(line) = line {
+        value["line"] = json!(line);
+    }
+    value
+}
+
+fn sampled_profile(
+    name: &str,
+    unit: &str,
+    samples: &[Vec<usize>],
+    weights: &[usize],
+) -> serde_json::Value {
+    json!({
+        "type": "sampled",
+        "name": name,
+        "unit": unit,
+        "startValue": 0,
+        "endValue": weights.iter().sum::<usize>(),
+        "samples": samples,
+        "weights": weights,
+    })
+}
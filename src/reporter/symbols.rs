@@ -0,0 +1,136 @@
+//! Simplified spellings of the symbol names in stack frames.
+//!
+//! Frames store generics with `‹›` in place of angle brackets, and this module
+//! works on that form so the result can be stored back into a snapshot.
+
+use super::{Reporter, SymbolStyle};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+// `{{closure}}` from legacy mangling and `{closure#0}` from v0
+static CLOSURE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\{\{closure\}\}|\{closure#\d+\}").unwrap());
+// v0 shims such as `{shim:vtable#0}`
+static SHIM: Lazy<Regex> = Lazy::new(|| Regex::new(r"\{shim:[^}]*\}").unwrap());
+// Anonymous impl blocks as named on Windows
+static IMPL: Lazy<Regex> = Lazy::new(|| Regex::new(r"impl\$\d+").unwrap());
+
+const STD_CRATES: &[&str] = &["std", "core", "alloc"];
+
+/// Rewrite a `function (file:line)` frame in the given style
+pub(crate) fn simplify(frame: &str, style: SymbolStyle) -> String {
+    if style == SymbolStyle::Full {
+        return frame.to_string();
+    }
+
+    let (function, file, line) = Reporter::split_frame(frame);
+    let name = Reporter::strip_disambiguators(function);
+    let name = CLOSURE.replace_all(&name, "{closure}");
+    let name = SHIM.replace_all(&name, "{shim}");
+    let name = IMPL.replace_all(&name, "{impl}");
+    let mut name = strip_generics(&name);
+    if style == SymbolStyle::Short {
+        name = shorten_std_paths(&name);
+    }
+
+    match (file, line) {
+        (Some(file), Some(line)) => format!("{} ({}:{})", name, file, line),
+        (Some(file), None) => format!("{} ({})", name, file),
+        _ => name,
+    }
+}
+
+// Drop generic arguments and turbofishes, and unwrap `‹Type›::method`, while
+// keeping the `‹Type as Trait›` of trait methods
+fn strip_generics(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut rest = name;
+
+    while let Some(open) = rest.find('‹') {
+        let before = &rest[..open];
+        let inner_start = open + '‹'.len_utf8();
+        let Some(len) = matching_close(&rest[inner_start..]) else {
+            break;
+        };
+        let inner = &rest[inner_start..inner_start + len];
+        let after = &rest[inner_start + len + '›'.len_utf8()..];
+
+        let follows_name = before
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_');
+        if let Some(before) = before.strip_suffix("::") {
+            // Turbofish
+            out.push_str(before);
+        } else if follows_name {
+            // Generic arguments of a type or function
+            out.push_str(before);
+        } else {
+            // Qualified path at the start of a name or argument
+            out.push_str(before);
+            let inner = strip_generics(inner);
+            if inner.contains(" as ") {
+                out.push('‹');
+                out.push_str(&inner);
+                out.push('›');
+            } else {
+                out.push_str(&inner);
+            }
+        }
+        rest = after;
+    }
+
+    out.push_str(rest);
+    out
+}
+
+// Length of the text before the `›` that closes an already opened `‹`
+fn matching_close(text: &str) -> Option<usize> {
+    let mut depth = 1;
+    for (i, c) in text.char_indices() {
+        match c {
+            '‹' => depth += 1,
+            '›' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// `alloc::vec::Vec::push` -> `Vec::push`, `std::rt::lang_start` ->
+// `rt::lang_start`: standard library paths keep their type onwards, or their
+// last two segments when no type is named
+fn shorten_std_paths(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut token = String::new();
+    let is_path_char = |c: char| c.is_alphanumeric() || "_:{}#".contains(c);
+
+    for c in name.chars().chain(std::iter::once('\0')) {
+        if is_path_char(c) {
+            token.push(c);
+            continue;
+        }
+        out.push_str(&shorten_path(&token));
+        token.clear();
+        if c != '\0' {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn shorten_path(path: &str) -> String {
+    let segments: Vec<&str> = path.split("::").collect();
+    if segments.len() <= 2 || !STD_CRATES.contains(&segments[0]) {
+        return path.to_string();
+    }
+    let start = segments
+        .iter()
+        .position(|segment| segment.starts_with(char::is_uppercase))
+        .unwrap_or(segments.len() - 2);
+    segments[start..].join("::")
+}
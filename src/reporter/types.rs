@@ -0,0 +1,278 @@
+//! Recovers the allocated Rust type from container and smart pointer frames.
+//!
+//! The type comes from the generic arguments in symbol names, so it is only
+//! as precise as the symbols: inlined frames carry the declared parameters
+//! (`Vec<T, A>`) rather than the concrete types, and the standard library's
+//! own copies of generic code are shared by every type of the same layout,
+//! so `Vec<String>::push` may run `RawVec<Vec<u8>>::grow_one`.
+
+use super::Reporter;
+
+// Containers by name, with how many leading generic arguments identify the
+// allocated type (the rest are allocators and hashers)
+const CONTAINERS: &[(&str, &str, usize)] = &[
+    ("Vec", "Vec", 1),
+    ("RawVec", "Vec", 1),
+    ("VecDeque", "VecDeque", 1),
+    ("String", "String", 0),
+    ("Box", "Box", 1),
+    ("Rc", "Rc", 1),
+    ("Arc", "Arc", 1),
+    ("BTreeMap", "BTreeMap", 2),
+    ("BTreeSet", "BTreeSet", 1),
+    ("BinaryHeap", "BinaryHeap", 1),
+    ("LinkedList", "LinkedList", 1),
+    ("HashMap", "HashMap", 2),
+    ("HashSet", "HashSet", 1),
+];
+
+// Crates whose frames make up the inside of a collection
+const COLLECTION_CRATES: &[&str] = &["alloc", "std", "hashbrown"];
+
+// Standard library crates, skipped when looking for the caller of a container
+const STD_CRATES: &[&str] = &["alloc", "core", "std", "hashbrown"];
+
+/// The allocated type of a whole stack: the type of the container frame
+/// nearest the caller, so `String::with_capacity` beats the `Vec<u8>` inside
+/// it. Arguments of the frames inside it are not used, as they may belong to
+/// a shared copy for another type. When the type has unknown arguments, the
+/// calling function is added (`Vec<_> in app::load`) so that containers of
+/// different callers are not lumped together.
+pub(crate) fn allocated_type(frames: &[String]) -> Option<String> {
+    let mut outermost: Option<(String, bool)> = None;
+    let mut caller = None;
+
+    for (i, frame) in frames.iter().enumerate() {
+        let path = symbol_path(frame);
+        match container_type(&path) {
+            Some(container) => outermost = Some(container),
+            None if outermost.is_none() => {}
+            // Once a container was seen, leaving the collection code ends it
+            None if COLLECTION_CRATES.contains(&crate_name(&path)) => {}
+            None => {
+                caller = frames[i..]
+                    .iter()
+                    .map(|frame| symbol_path(frame))
+                    .find(|path| !STD_CRATES.contains(&crate_name(path)));
+                break;
+            }
+        }
+    }
+
+    match (outermost?, caller) {
+        ((name, false), Some(caller)) => Some(format!("{} in {}", name, caller)),
+        ((name, _), _) => Some(name),
+    }
+}
+
+/// The allocated type named by a single frame
+pub(crate) fn frame_type(frame: &str) -> Option<String> {
+    container_type(&symbol_path(frame)).map(|(name, _)| name)
+}
+
+// Real symbol path of a frame, without location or crate disambiguators
+fn symbol_path(frame: &str) -> String {
+    let (function, _, _) = Reporter::split_frame(frame);
+    Reporter::strip_disambiguators(&Reporter::restore_generics(function))
+}
+
+fn crate_name(path: &str) -> &str {
+    let path = path.trim_start_matches(['<', '&']);
+    path.split("::").next().unwrap_or(path)
+}
+
+// The container type a symbol belongs to, and whether its generic arguments
+// are concrete types rather than declared parameters
+fn container_type(path: &str) -> Option<(String, bool)> {
+    let self_type = impl_self_type(path);
+    if !COLLECTION_CRATES.contains(&crate_name(self_type)) {
+        return None;
+    }
+
+    for segment in split_top_level(self_type, "::") {
+        let (base, args) = match segment.find('<') {
+            Some(open) => {
+                let args = &segment[open + 1..];
+                (&segment[..open], args.strip_suffix('>').unwrap_or(args))
+            }
+            None => (segment, ""),
+        };
+        let Some(&(_, display, arity)) = CONTAINERS.iter().find(|(name, _, _)| *name == base)
+        else {
+            continue;
+        };
+
+        if arity == 0 {
+            return Some((display.to_string(), true));
+        }
+        let args: Vec<String> = split_top_level(args, ",")
+            .into_iter()
+            .take(arity)
+            .map(|arg| short_type(arg.trim()))
+            .collect();
+        if args.is_empty() {
+            return Some((format!("{}<_>", display), false));
+        }
+        let concrete = !args.iter().any(|arg| is_type_parameter(arg));
+        let args: Vec<&str> = args
+            .iter()
+            .map(|arg| if is_type_parameter(arg) { "_" } else { arg })
+            .collect();
+        return Some((format!("{}<{}>", display, args.join(", ")), concrete));
+    }
+    None
+}
+
+// `<Type as Trait>::method` and `<Type>::method` name `Type`; anything else
+// is a plain path
+fn impl_self_type(path: &str) -> &str {
+    if !path.starts_with('<') {
+        return path;
+    }
+    let mut depth = 0;
+    for (i, c) in path.char_indices() {
+        match c {
+            '<' => depth += 1,
+            '>' => {
+                depth -= 1;
+                if depth == 0 {
+                    let inner = &path[1..i];
+                    return split_top_level(inner, " as ")[0];
+                }
+            }
+            _ => {}
+        }
+    }
+    path
+}
+
+// Split on a separator that is not nested inside `<>`, `()` or `[]`
+fn split_top_level<'a>(text: &'a str, separator: &str) -> Vec<&'a str> {
+    let mut parts = Vec::new();
+    let mut depth = 0i32;
+    let mut start = 0;
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        let c = rest.chars().next().unwrap_or(' ');
+        match c {
+            '<' | '(' | '[' => depth += 1,
+            '>' | ')' | ']' => depth -= 1,
+            _ if depth == 0 && rest.starts_with(separator) => {
+                parts.push(&text[start..i]);
+                i += separator.len();
+                start = i;
+                continue;
+            }
+            _ => {}
+        }
+        i += c.len_utf8();
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+// Drop module paths from every type name: `alloc::vec::Vec<u8>` -> `Vec<u8>`
+fn short_type(ty: &str) -> String {
+    let mut short = String::with_capacity(ty.len());
+    let mut token = String::new();
+    for c in ty.chars().chain(std::iter::once('\0')) {
+        if c.is_alphanumeric() || c == '_' || c == ':' {
+            token.push(c);
+            continue;
+        }
+        short.push_str(token.rsplit("::").next().unwrap_or(&token));
+        token.clear();
+        if c != '\0' {
+            short.push(c);
+        }
+    }
+    short
+}
+
+// Declared parameters such as `T`, `K` or `A`, as opposed to real types
+fn is_type_parameter(ty: &str) -> bool {
+    !ty.is_empty() && ty.len() <= 2 && ty.chars().all(|c| c.is_ascii_uppercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn trait_impls_name_their_self_type() {
+        assert_eq!(
+            impl_self_type("<alloc::string::String as core::clone::Clone>::clone"),
+            "alloc::string::String"
+        );
+        assert_eq!(
+            impl_self_type("<alloc::vec::Vec<u8>>::with_capacity"),
+            "alloc::vec::Vec<u8>"
+        );
+        assert_eq!(
+            container_type("<alloc::string::String as core::clone::Clone>::clone"),
+            Some(("String".to_string(), true))
+        );
+    }
+
+    #[test]
+    fn nested_generics_keep_their_arguments() {
+        assert_eq!(
+            container_type(
+                "<alloc::vec::Vec<std::collections::hash::map::HashMap<alloc::string::String, u32>>>::push"
+            ),
+            Some(("Vec<HashMap<String, u32>>".to_string(), true))
+        );
+        assert_eq!(
+            container_type(
+                "std::collections::hash::map::HashMap<alloc::string::String, alloc::vec::Vec<u8>, std::hash::random::RandomState>::insert"
+            ),
+            Some(("HashMap<String, Vec<u8>>".to_string(), true))
+        );
+        assert_eq!(
+            container_type("alloc::vec::Vec<T,A>::push"),
+            Some(("Vec<_>".to_string(), false))
+        );
+    }
+
+    #[test]
+    fn boxed_str() {
+        assert_eq!(
+            frame_type("‹alloc[fdfd2bd8633a6659]::boxed::Box‹str››::clone_from_ref"),
+            Some("Box<str>".to_string())
+        );
+    }
+
+    #[test]
+    fn outermost_container_wins() {
+        let stack = frames(&[
+            "‹alloc::raw_vec::RawVec‹u8››::with_capacity_in",
+            "‹alloc::vec::Vec‹u8››::with_capacity",
+            "‹alloc::string::String›::with_capacity",
+            "alloc::fmt::format::format_inner",
+            "app::main",
+        ]);
+        assert_eq!(allocated_type(&stack), Some("String".to_string()));
+    }
+
+    #[test]
+    fn shared_copies_do_not_name_the_type() {
+        // `Vec<String>::push` inlined into the caller, running the standard
+        // library's copy for another type of the same layout
+        let stack = frames(&[
+            "‹alloc::raw_vec::RawVec‹alloc::vec::Vec‹u8›››::grow_one",
+            "alloc::vec::Vec‹T,A›::push",
+            "core::ops::function::FnOnce::call_once",
+            "app::load",
+            "app::main",
+        ]);
+        assert_eq!(
+            allocated_type(&stack),
+            Some("Vec<_> in app::load".to_string())
+        );
+    }
+}
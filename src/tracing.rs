@@ -0,0 +1,41 @@
+//! Attribution of allocations to `tracing` spans.
+//!
+//! [`AllocationLayer`] follows the spans entered on each thread and tags
+//! every allocation recorded by [`AllocationProfiler`] with the path of the
+//! innermost one, such as `request > parse`. Instrumented futures enter their
+//! span on every poll, so allocations made after an `.await` are still
+//! charged to the right request. Report by span with `--group-by span`.
+//!
+//! ```no_run
+//! use cargo_alloc_profile::tracing::AllocationLayer;
+//! use tracing_subscriber::prelude::*;
+//!
+//! tracing_subscriber::registry().with(AllocationLayer).init();
+//! ```
+
+use crate::profiler::AllocationProfiler;
+use ::tracing::Subscriber;
+use ::tracing::span::Id;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// A `tracing_subscriber` layer that tags allocations with the active span
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllocationLayer;
+
+impl<S> Layer<S> for AllocationLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            AllocationProfiler::enter_span(span.name());
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        if ctx.span(id).is_some() {
+            AllocationProfiler::exit_span();
+        }
+    }
+}
@@ -0,0 +1,621 @@
+//! Live terminal dashboard for `cargo alloc-profile watch`.
+//!
+//! The dashboard shows the profile of the most recently started process as
+//! its updates arrive through the [`Collector`], along with the output of the
+//! build and of the program.
+
+use crate::collect::{Collector, Process};
+use cargo_alloc_profile::ProfileSnapshot;
+use cargo_alloc_profile::reporter::{ReportOptions, Reporter, SiteSummary};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Cell, Paragraph, Row, Sparkline, Table};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+// Memory samples and output lines kept for display
+const HISTORY_LEN: usize = 512;
+const OUTPUT_LEN: usize = 200;
+
+/// How the profiled program ended and what its processes sent
+pub struct Outcome {
+    /// `None` if the program was still running when the dashboard was closed
+    pub status: Option<ExitStatus>,
+    pub processes: Vec<Process>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    Count,
+    Bytes,
+    Live,
+    Name,
+}
+
+impl SortKey {
+    fn next(self) -> Self {
+        match self {
+            SortKey::Count => SortKey::Bytes,
+            SortKey::Bytes => SortKey::Live,
+            SortKey::Live => SortKey::Name,
+            SortKey::Name => SortKey::Count,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            SortKey::Count => "count",
+            SortKey::Bytes => "bytes",
+            SortKey::Live => "live",
+            SortKey::Name => "name",
+        }
+    }
+}
+
+struct Dashboard<'a> {
+    options: &'a ReportOptions,
+    // Index of the process shown; later processes replace earlier ones
+    process: Option<usize>,
+    // Totals and sites of the process shown, without its history
+    snapshot: Option<ProfileSnapshot>,
+    // Time between updates from the program, which sends none while idle
+    interval: Duration,
+    updated: Instant,
+    sites: Vec<SiteSummary>,
+    memory: VecDeque<u64>,
+    allocation_rate: f64,
+    byte_rate: f64,
+    output: VecDeque<String>,
+    sort: SortKey,
+    filter: String,
+    editing_filter: bool,
+    paused: bool,
+    status: Option<ExitStatus>,
+}
+
+/// Run `cmd` with its snapshots streamed to a live dashboard until the user
+/// quits. The program is killed if it is still running by then.
+pub fn run(
+    cmd: &mut Command,
+    interval_ms: u64,
+    options: &ReportOptions,
+) -> Result<Outcome, Box<dyn Error>> {
+    let mut collector = Collector::start(cmd, Some(interval_ms))?;
+
+    // The dashboard owns the terminal, so output is shown in a pane instead.
+    // Standard input is detached rather than shared, as the dashboard reads
+    // its keys from it and the program would take some of them.
+    cmd.stdin(Stdio::null());
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+    let mut child = cmd.spawn()?;
+
+    let (sender, output) = mpsc::channel();
+    if let Some(stdout) = child.stdout.take() {
+        forward_output(stdout, sender.clone());
+    }
+    if let Some(stderr) = child.stderr.take() {
+        forward_output(stderr, sender);
+    }
+
+    let mut dashboard = Dashboard::new(options, Duration::from_millis(interval_ms));
+
+    let mut terminal = ratatui::init();
+    let result = dashboard.run(&mut terminal, &mut child, &mut collector, &output);
+    ratatui::restore();
+
+    if dashboard.status.is_none() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+    result?;
+
+    Ok(Outcome {
+        status: dashboard.status,
+        processes: collector.finish(),
+    })
+}
+
+// Send each line the program writes to `pipe` to the dashboard
+fn forward_output(pipe: impl Read + Send + 'static, sender: Sender<String>) {
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(pipe);
+        let mut line = Vec::new();
+        while reader
+            .read_until(b'\n', &mut line)
+            .is_ok_and(|read| read > 0)
+        {
+            let text = String::from_utf8_lossy(&line).trim_end().to_string();
+            if sender.send(text).is_err() {
+                break;
+            }
+            line.clear();
+        }
+    });
+}
+
+impl<'a> Dashboard<'a> {
+    fn new(options: &'a ReportOptions, interval: Duration) -> Self {
+        Dashboard {
+            options,
+            process: None,
+            snapshot: None,
+            interval,
+            updated: Instant::now(),
+            sites: Vec::new(),
+            memory: VecDeque::new(),
+            allocation_rate: 0.0,
+            byte_rate: 0.0,
+            output: VecDeque::new(),
+            sort: SortKey::Count,
+            filter: String::new(),
+            editing_filter: false,
+            paused: false,
+            status: None,
+        }
+    }
+
+    fn run(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        child: &mut Child,
+        collector: &mut Collector,
+        output: &Receiver<String>,
+    ) -> Result<(), Box<dyn Error>> {
+        loop {
+            // An idle program sends nothing, so its rates drop to zero and
+            // its memory history moves on without it
+            let changed = collector.poll();
+            if !self.paused && (changed || self.updated.elapsed() >= self.interval) {
+                self.update(collector.processes());
+            }
+            while let Ok(line) = output.try_recv() {
+                if self.output.len() == OUTPUT_LEN {
+                    self.output.pop_front();
+                }
+                self.output.push_back(line);
+            }
+            if self.status.is_none() {
+                self.status = child.try_wait()?;
+            }
+
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if event::poll(Duration::from_millis(100))?
+                && let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+                && self.handle_key(key.code, key.modifiers)
+            {
+                return Ok(());
+            }
+        }
+    }
+
+    fn update(&mut self, processes: &[Process]) {
+        // Show the latest process that has started profiling
+        let Some((index, process)) = processes
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, process)| process.snapshot.pid != 0)
+        else {
+            return;
+        };
+        let now = Instant::now();
+
+        // A new process starts with a fresh history
+        if self.process != Some(index) {
+            self.process = Some(index);
+            self.snapshot = None;
+            self.memory.clear();
+            self.allocation_rate = 0.0;
+            self.byte_rate = 0.0;
+        }
+
+        let snapshot = ProfileSnapshot {
+            total_allocations: process.snapshot.total_allocations,
+            total_deallocations: process.snapshot.total_deallocations,
+            total_bytes_allocated: process.snapshot.total_bytes_allocated,
+            peak_memory: process.snapshot.peak_memory,
+            current_memory: process.snapshot.current_memory,
+            allocation_sites: process.snapshot.allocation_sites.clone(),
+            pid: process.snapshot.pid,
+            command: process.snapshot.command.clone(),
+            ..ProfileSnapshot::default()
+        };
+
+        if let Some(previous) = &self.snapshot {
+            let seconds = now.duration_since(self.updated).as_secs_f64();
+            if seconds > 0.0 {
+                self.allocation_rate = snapshot
+                    .total_allocations
+                    .saturating_sub(previous.total_allocations)
+                    as f64
+                    / seconds;
+                self.byte_rate = snapshot
+                    .total_bytes_allocated
+                    .saturating_sub(previous.total_bytes_allocated)
+                    as f64
+                    / seconds;
+            }
+        }
+        self.updated = now;
+
+        if self.memory.len() == HISTORY_LEN {
+            self.memory.pop_front();
+        }
+        self.memory.push_back(snapshot.current_memory as u64);
+
+        self.sites = Reporter::summarize(snapshot.clone(), self.options);
+        self.snapshot = Some(snapshot);
+    }
+
+    // Returns true when the dashboard should close
+    fn handle_key(&mut self, code: KeyCode, modifiers: KeyModifiers) -> bool {
+        if modifiers.contains(KeyModifiers::CONTROL) && code == KeyCode::Char('c') {
+            return true;
+        }
+
+        if self.editing_filter {
+            match code {
+                KeyCode::Char(c) => self.filter.push(c),
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                }
+                KeyCode::Enter => self.editing_filter = false,
+                KeyCode::Esc => {
+                    self.filter.clear();
+                    self.editing_filter = false;
+                }
+                _ => {}
+            }
+            return false;
+        }
+
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return true,
+            KeyCode::Char('s') => self.sort = self.sort.next(),
+            KeyCode::Char('/') => self.editing_filter = true,
+            KeyCode::Char('c') => self.filter.clear(),
+            KeyCode::Char('p') => self.paused = !self.paused,
+            _ => {}
+        }
+        false
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [header, memory, stats, table, output, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(7),
+            Constraint::Length(1),
+            Constraint::Min(5),
+            Constraint::Length(8),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        frame.render_widget(Paragraph::new(self.header_line()), header);
+        self.draw_memory(frame, memory);
+        frame.render_widget(Paragraph::new(self.stats_line()), stats);
+        self.draw_sites(frame, table);
+        self.draw_output(frame, output);
+        frame.render_widget(Paragraph::new(self.footer_line()), footer);
+    }
+
+    fn header_line(&self) -> Line<'_> {
+        let state = match (&self.status, self.paused) {
+            (Some(status), _) if status.success() => {
+                Span::styled("exited", Style::default().fg(Color::Green))
+            }
+            (Some(status), _) => Span::styled(
+                format!("failed ({})", status),
+                Style::default().fg(Color::Red),
+            ),
+            (None, true) => Span::styled("paused", Style::default().fg(Color::Yellow)),
+            (None, false) => Span::styled("running", Style::default().fg(Color::Green)),
+        };
+        let program = match &self.snapshot {
+            Some(snapshot) => format!("{} (pid {})", snapshot.command, snapshot.pid),
+            None if self.status.is_some() => String::new(),
+            None => "waiting for the program to start…".to_string(),
+        };
+        let hint = if self.status.is_some() {
+            "  press q for the report"
+        } else {
+            ""
+        };
+        Line::from(vec![
+            Span::styled(
+                "cargo alloc-profile watch ",
+                Style::default().add_modifier(Modifier::BOLD),
+            ),
+            state,
+            Span::raw("  "),
+            Span::raw(program),
+            Span::styled(hint, Style::default().add_modifier(Modifier::DIM)),
+        ])
+    }
+
+    fn draw_memory(&self, frame: &mut Frame, area: Rect) {
+        let (current, peak) = self
+            .snapshot
+            .as_ref()
+            .map_or((0, 0), |s| (s.current_memory, s.peak_memory));
+        let title = format!(
+            " Memory: current {}, peak {} ",
+            kilobytes(current),
+            kilobytes(peak)
+        );
+
+        // Show the most recent samples that fit
+        let width = area.width.saturating_sub(2) as usize;
+        let skip = self.memory.len().saturating_sub(width);
+        let data: Vec<u64> = self.memory.iter().skip(skip).copied().collect();
+
+        let sparkline = Sparkline::default()
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .data(&data)
+            .max(peak.max(1) as u64)
+            .style(Style::default().fg(Color::Cyan));
+        frame.render_widget(sparkline, area);
+    }
+
+    fn stats_line(&self) -> Line<'_> {
+        let Some(snapshot) = &self.snapshot else {
+            return Line::default();
+        };
+        Line::from(format!(
+            " Allocations: {} ({:.0}/s)   Allocated: {} ({}/s)   Live: {}",
+            snapshot.total_allocations,
+            self.allocation_rate,
+            kilobytes(snapshot.total_bytes_allocated),
+            kilobytes(self.byte_rate as usize),
+            snapshot
+                .total_allocations
+                .saturating_sub(snapshot.total_deallocations),
+        ))
+    }
+
+    // The sites that match the filter, in the chosen order
+    fn visible_sites(&self) -> Vec<&SiteSummary> {
+        let filter = self.filter.to_lowercase();
+        let mut sites: Vec<&SiteSummary> = self
+            .sites
+            .iter()
+            .filter(|site| site.name.to_lowercase().contains(&filter))
+            .collect();
+        match self.sort {
+            SortKey::Count => sites.sort_by_key(|site| std::cmp::Reverse(site.count)),
+            SortKey::Bytes => sites.sort_by_key(|site| std::cmp::Reverse(site.total_bytes)),
+            SortKey::Live => sites.sort_by_key(|site| std::cmp::Reverse(site.live_bytes)),
+            SortKey::Name => sites.sort_by(|a, b| a.name.cmp(&b.name)),
+        }
+        sites
+    }
+
+    fn draw_sites(&self, frame: &mut Frame, area: Rect) {
+        let sites = self.visible_sites();
+        let shown = area.height.saturating_sub(3) as usize;
+        let limit = self.options.limit.map_or(shown, |limit| limit.min(shown));
+
+        let rows = sites.iter().take(limit).map(|site| {
+            Row::new(vec![
+                Cell::from(site.name.clone()),
+                Cell::from(site.count.to_string()),
+                Cell::from(kilobytes(site.total_bytes)),
+                Cell::from(kilobytes(site.live_bytes)),
+            ])
+        });
+        let header = Row::new(["Site", "Count", "Bytes", "Live"].map(|title| {
+            let style = if title.eq_ignore_ascii_case(self.sort.label())
+                || (title == "Site" && self.sort == SortKey::Name)
+            {
+                Style::default().add_modifier(Modifier::BOLD | Modifier::UNDERLINED)
+            } else {
+                Style::default().add_modifier(Modifier::BOLD)
+            };
+            Cell::from(title).style(style)
+        }));
+        let title = format!(
+            " Top sites ({} of {}) ",
+            limit.min(sites.len()),
+            sites.len()
+        );
+
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Min(20),
+                Constraint::Length(10),
+                Constraint::Length(14),
+                Constraint::Length(14),
+            ],
+        )
+        .header(header)
+        .block(Block::default().borders(Borders::ALL).title(title));
+        frame.render_widget(table, area);
+    }
+
+    fn draw_output(&self, frame: &mut Frame, area: Rect) {
+        let shown = area.height.saturating_sub(2) as usize;
+        let skip = self.output.len().saturating_sub(shown);
+        let lines: Vec<Line> = self
+            .output
+            .iter()
+            .skip(skip)
+            .map(|line| Line::from(line.as_str()))
+            .collect();
+        let output = Paragraph::new(lines)
+            .block(Block::default().borders(Borders::ALL).title(" Output "))
+            .style(Style::default().fg(Color::DarkGray));
+        frame.render_widget(output, area);
+    }
+
+    fn footer_line(&self) -> Line<'_> {
+        let filter = if self.editing_filter {
+            format!("filter: {}▏ (enter to apply, esc to clear)", self.filter)
+        } else if self.filter.is_empty() {
+            "/ filter".to_string()
+        } else {
+            format!("/ filter: {}  c clear", self.filter)
+        };
+        Line::from(format!(
+            " q quit  s sort: {}  {}  p {}",
+            self.sort.label(),
+            filter,
+            if self.paused { "resume" } else { "pause" }
+        ))
+        .style(Style::default().add_modifier(Modifier::REVERSED))
+    }
+}
+
+fn kilobytes(bytes: usize) -> String {
+    format!("{:.2} KB", bytes as f64 / 1024.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cargo_alloc_profile::AllocationSite;
+
+    const INTERVAL: Duration = Duration::from_millis(250);
+
+    fn site(function: &str, count: usize, total_bytes: usize, live_bytes: usize) -> AllocationSite {
+        serde_json::from_value(serde_json::json!({
+            "count": count,
+            "total_bytes": total_bytes,
+            "live_bytes": live_bytes,
+            "frames": [format!("{} (src/main.rs:1)", function)],
+        }))
+        .unwrap()
+    }
+
+    fn process(pid: u32, sites: Vec<AllocationSite>) -> Process {
+        let mut snapshot = ProfileSnapshot {
+            pid,
+            ..ProfileSnapshot::default()
+        };
+        for site in sites {
+            snapshot.total_allocations += site.count;
+            snapshot.allocation_sites.insert(site.key(), site);
+        }
+        Process::finished(snapshot)
+    }
+
+    fn names(dashboard: &Dashboard) -> Vec<String> {
+        dashboard
+            .visible_sites()
+            .iter()
+            .map(|site| site.name.clone())
+            .collect()
+    }
+
+    fn press(dashboard: &mut Dashboard, code: KeyCode) -> bool {
+        dashboard.handle_key(code, KeyModifiers::NONE)
+    }
+
+    fn dashboard_with_sites(options: &ReportOptions) -> Dashboard<'_> {
+        let mut dashboard = Dashboard::new(options, INTERVAL);
+        dashboard.update(&[process(
+            1,
+            vec![
+                site("app::parse", 30, 100, 0),
+                site("app::load", 10, 900, 50),
+                site("app::render", 20, 300, 200),
+            ],
+        )]);
+        dashboard
+    }
+
+    #[test]
+    fn update_shows_latest_started_process() {
+        let options = ReportOptions::default();
+        let mut dashboard = Dashboard::new(&options, INTERVAL);
+        dashboard.update(&[
+            process(1, vec![site("first::main", 1, 8, 0)]),
+            process(2, vec![site("second::main", 1, 8, 0)]),
+            process(0, Vec::new()),
+        ]);
+
+        assert_eq!(dashboard.snapshot.as_ref().unwrap().pid, 2);
+        assert_eq!(names(&dashboard), ["second::main"]);
+    }
+
+    #[test]
+    fn sort_key_cycles() {
+        let options = ReportOptions::default();
+        let mut dashboard = dashboard_with_sites(&options);
+        assert_eq!(
+            names(&dashboard),
+            ["app::parse", "app::render", "app::load"]
+        );
+
+        press(&mut dashboard, KeyCode::Char('s'));
+        assert_eq!(dashboard.sort, SortKey::Bytes);
+        assert_eq!(
+            names(&dashboard),
+            ["app::load", "app::render", "app::parse"]
+        );
+
+        press(&mut dashboard, KeyCode::Char('s'));
+        assert_eq!(
+            names(&dashboard),
+            ["app::render", "app::load", "app::parse"]
+        );
+
+        press(&mut dashboard, KeyCode::Char('s'));
+        assert_eq!(
+            names(&dashboard),
+            ["app::load", "app::parse", "app::render"]
+        );
+
+        press(&mut dashboard, KeyCode::Char('s'));
+        assert_eq!(dashboard.sort, SortKey::Count);
+    }
+
+    #[test]
+    fn filter_is_edited_and_cleared() {
+        let options = ReportOptions::default();
+        let mut dashboard = dashboard_with_sites(&options);
+
+        press(&mut dashboard, KeyCode::Char('/'));
+        for c in "LOA".chars() {
+            assert!(!press(&mut dashboard, KeyCode::Char(c)));
+        }
+        press(&mut dashboard, KeyCode::Enter);
+        assert!(!dashboard.editing_filter);
+        assert_eq!(names(&dashboard), ["app::load"]);
+
+        // Keys that would quit or sort only edit the filter while editing
+        press(&mut dashboard, KeyCode::Char('/'));
+        assert!(!press(&mut dashboard, KeyCode::Char('q')));
+        assert_eq!(dashboard.filter, "LOAq");
+        press(&mut dashboard, KeyCode::Esc);
+        assert!(dashboard.filter.is_empty());
+        assert_eq!(names(&dashboard).len(), 3);
+
+        press(&mut dashboard, KeyCode::Char('/'));
+        press(&mut dashboard, KeyCode::Char('r'));
+        press(&mut dashboard, KeyCode::Enter);
+        assert_eq!(names(&dashboard), ["app::parse", "app::render"]);
+        press(&mut dashboard, KeyCode::Char('c'));
+        assert_eq!(names(&dashboard).len(), 3);
+    }
+
+    #[test]
+    fn quit_keys() {
+        let options = ReportOptions::default();
+        let mut dashboard = Dashboard::new(&options, INTERVAL);
+        assert!(!press(&mut dashboard, KeyCode::Char('p')));
+        assert!(dashboard.paused);
+        assert!(press(&mut dashboard, KeyCode::Char('q')));
+        assert!(dashboard.handle_key(KeyCode::Char('c'), KeyModifiers::CONTROL));
+    }
+}
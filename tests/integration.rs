@@ -1,3 +1,6 @@
+// The baseline tests keep their nested `if let`s, which this lint flags
+#![allow(clippy::collapsible_if)]
+
 use std::process::Command;
 use tempfile::TempDir;
 
@@ -311,3 +314,1441 @@ fn test_help_output() {
     assert!(stdout.contains("--compare"), "Should document --compare");
     assert!(stdout.contains("--group-by"), "Should document --group-by");
 }
+
+#[test]
+fn test_pprof_output() {
+    use std::io::Read;
+
+    let temp_dir = TempDir::new().expect("Failed to create temp dir");
+    let profile_path = temp_dir.path().join("profile.pb.gz");
+
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "-o",
+            "pprof",
+            "--output-file",
+            profile_path.to_str().unwrap(),
+            "run",
+            "--example",
+            "simple",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Command should succeed");
+
+    let compressed = std::fs::read(&profile_path).expect("Profile should be written");
+    let mut decoder = flate2::read::GzDecoder::new(&compressed[..]);
+    let mut profile = Vec::new();
+    decoder
+        .read_to_end(&mut profile)
+        .expect("Profile should be gzipped");
+
+    let profile = String::from_utf8_lossy(&profile);
+    for sample_type in [
+        "alloc_objects",
+        "alloc_space",
+        "inuse_objects",
+        "inuse_space",
+    ] {
+        assert!(
+            profile.contains(sample_type),
+            "Should declare {} sample type",
+            sample_type
+        );
+    }
+    assert!(
+        profile.contains("simple::main"),
+        "Should contain user frames"
+    );
+}
+
+#[test]
+fn test_speedscope_output() {
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "-o",
+            "speedscope",
+            "run",
+            "--example",
+            "simple",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Command should succeed");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let json: serde_json::Value =
+        serde_json::from_str(&stdout).expect("Output should be valid JSON");
+
+    let frames = json["shared"]["frames"]
+        .as_array()
+        .expect("Should have a shared frame table");
+    let profiles = json["profiles"].as_array().expect("Should have profiles");
+    assert_eq!(profiles.len(), 2, "Should have bytes and count profiles");
+
+    for profile in profiles {
+        assert_eq!(profile["type"], "sampled");
+        let samples = profile["samples"].as_array().unwrap();
+        let weights = profile["weights"].as_array().unwrap();
+        assert_eq!(samples.len(), weights.len());
+        for index in samples.iter().flat_map(|s| s.as_array().unwrap()) {
+            assert!((index.as_u64().unwrap() as usize) < frames.len());
+        }
+    }
+}
+
+#[test]
+fn test_chrome_trace_output() {
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "-o",
+            "chrome-trace",
+            "--large-alloc-bytes",
+            "256",
+            "run",
+            "--example",
+            "simple",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Command should succeed");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let json: serde_json::Value =
+        serde_json::from_str(&stdout).expect("Output should be valid JSON");
+    let events = json["traceEvents"]
+        .as_array()
+        .expect("Should have trace events");
+
+    assert!(
+        events.iter().any(|e| e["ph"] == "C" && e["name"] == "Heap"),
+        "Should have a heap counter track"
+    );
+    assert!(
+        events
+            .iter()
+            .any(|e| e["ph"] == "M" && e["name"] == "thread_name"),
+        "Should name thread tracks"
+    );
+    assert!(
+        events
+            .iter()
+            .any(|e| e["ph"] == "i" && e["args"]["stack"].is_array()),
+        "Should mark large allocations with their stacks"
+    );
+}
+
+#[test]
+fn test_massif_output() {
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "-o",
+            "massif",
+            "run",
+            "--example",
+            "simple",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Command should succeed");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(stdout.starts_with("desc: "), "Should start with a header");
+    assert!(stdout.contains("time_unit: ms"), "Should declare time unit");
+    assert_eq!(
+        stdout.matches("heap_tree=peak").count(),
+        1,
+        "Should have exactly one peak snapshot"
+    );
+    assert!(
+        stdout.contains("n2: ") || stdout.contains("n1: "),
+        "Should contain an allocation tree"
+    );
+    assert!(
+        stdout.contains("simple::main"),
+        "Tree should include user frames"
+    );
+}
+
+#[test]
+fn test_dhat_output() {
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "-o",
+            "dhat",
+            "run",
+            "--example",
+            "simple",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Command should succeed");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let json: serde_json::Value =
+        serde_json::from_str(&stdout).expect("Output should be valid JSON");
+
+    assert_eq!(json["dhatFileVersion"], 2);
+    let frame_table = json["ftbl"].as_array().expect("Should have a frame table");
+    let program_points = json["pps"].as_array().expect("Should have program points");
+    assert!(!program_points.is_empty(), "Should have program points");
+
+    for pp in program_points {
+        for field in ["tb", "tbk", "tl", "mb", "mbk", "gb", "gbk", "eb", "ebk"] {
+            assert!(pp[field].is_u64(), "Program point should have {}", field);
+        }
+        assert!(pp["mb"].as_u64() <= pp["tb"].as_u64());
+        for frame in pp["fs"].as_array().unwrap() {
+            let index = frame.as_u64().unwrap() as usize;
+            assert!(index > 0 && index < frame_table.len());
+        }
+    }
+}
+
+#[test]
+fn test_markdown_output() {
+    let temp_dir = TempDir::new().expect("Failed to create temp dir");
+    let baseline_path = temp_dir.path().join("baseline.json");
+
+    let save_output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "-o",
+            "markdown",
+            "--save",
+            baseline_path.to_str().unwrap(),
+            "run",
+            "--example",
+            "simple",
+        ])
+        .output()
+        .expect("Failed to execute save command");
+
+    assert!(save_output.status.success(), "Save command should succeed");
+    let stdout = String::from_utf8_lossy(&save_output.stdout);
+    assert!(stdout.starts_with("## Allocation Profile"));
+    assert!(stdout.contains("| # | Site | Count | Bytes |"));
+    assert!(stdout.contains("<details>"), "Should collapse stack traces");
+
+    let compare_output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "-o",
+            "markdown",
+            "--compare",
+            baseline_path.to_str().unwrap(),
+            "run",
+            "--example",
+            "simple",
+        ])
+        .output()
+        .expect("Failed to execute compare command");
+
+    assert!(
+        compare_output.status.success(),
+        "Compare command should succeed"
+    );
+    let stdout = String::from_utf8_lossy(&compare_output.stdout);
+    assert!(stdout.starts_with("## Allocation Comparison"));
+    assert!(stdout.contains("| Metric | Baseline | Current | Change |"));
+    assert!(
+        stdout.contains(" → "),
+        "Should show baseline to current deltas"
+    );
+}
+
+#[test]
+fn test_sarif_output() {
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "-o",
+            "sarif",
+            "run",
+            "--example",
+            "simple",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Command should succeed");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let json: serde_json::Value =
+        serde_json::from_str(&stdout).expect("Output should be valid JSON");
+
+    assert_eq!(json["version"], "2.1.0");
+    let results = json["runs"][0]["results"]
+        .as_array()
+        .expect("Should have results");
+    assert!(!results.is_empty(), "Should report hot sites");
+
+    for result in results {
+        let location = &result["locations"][0]["physicalLocation"];
+        assert_eq!(
+            location["artifactLocation"]["uri"], "examples/simple.rs",
+            "Results should be anchored to user code"
+        );
+        assert!(location["region"]["startLine"].is_u64());
+        assert!(result["codeFlows"][0]["threadFlows"][0]["locations"].is_array());
+    }
+}
+
+#[test]
+fn test_annotate_source() {
+    let temp_dir = TempDir::new().expect("Failed to create temp dir");
+    let profile_path = temp_dir.path().join("profile.json");
+
+    let save_output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "--save",
+            profile_path.to_str().unwrap(),
+            "run",
+            "--example",
+            "simple",
+        ])
+        .output()
+        .expect("Failed to execute save command");
+    assert!(save_output.status.success(), "Save command should succeed");
+
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "annotate",
+            "examples/simple.rs",
+            "--profile",
+            profile_path.to_str().unwrap(),
+        ])
+        .output()
+        .expect("Failed to execute annotate command");
+
+    assert!(output.status.success(), "Annotate command should succeed");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(stdout.contains("Allocation Annotations"));
+    let push_line = stdout
+        .lines()
+        .find(|l| l.contains("strings.push(format!"))
+        .expect("Should list the source");
+    assert!(
+        push_line.contains("KB"),
+        "The string-building line should be annotated"
+    );
+}
+
+#[test]
+fn test_call_tree_views() {
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "--view",
+            "tree",
+            "run",
+            "--example",
+            "simple",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Command should succeed");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Allocation Call Tree:"));
+    assert!(stdout.contains("└─"), "Should draw the tree");
+    assert!(stdout.contains("[self:"), "Should show exclusive totals");
+
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "-o",
+            "json",
+            "--view",
+            "inverted",
+            "--prune",
+            "0",
+            "run",
+            "--example",
+            "simple",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Command should succeed");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let json: serde_json::Value = serde_json::from_str(&stdout).expect("Output should be JSON");
+
+    let root = &json["call_tree"];
+    let children = root["children"]
+        .as_array()
+        .expect("Root should have children");
+    assert!(!children.is_empty());
+
+    // With nothing pruned, the allocating functions account for everything
+    let child_count: u64 = children.iter().map(|c| c["count"].as_u64().unwrap()).sum();
+    assert_eq!(child_count, root["count"].as_u64().unwrap());
+    for child in children {
+        assert_eq!(child["count"], child["self_count"]);
+    }
+}
+
+#[test]
+fn test_focus_ignore_hide() {
+    let run_json = |extra: &[&str]| -> serde_json::Value {
+        let mut args = vec![
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "-o",
+            "json",
+            "-vvv",
+        ];
+        args.extend_from_slice(extra);
+        args.extend_from_slice(&["run", "--example", "simple"]);
+        let output = Command::new("cargo")
+            .args(&args)
+            .output()
+            .expect("Failed to execute command");
+        assert!(output.status.success(), "Command should succeed");
+        serde_json::from_str(&String::from_utf8_lossy(&output.stdout))
+            .expect("Output should be JSON")
+    };
+    let stacks = |json: &serde_json::Value| -> Vec<Vec<String>> {
+        json["allocations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|a| serde_json::from_value(a["stack_trace"].clone()).unwrap())
+            .collect()
+    };
+
+    // Focused stacks end at the matching frame
+    let focused = run_json(&["--focus", r"simple::main\b"]);
+    assert!(!stacks(&focused).is_empty());
+    for stack in stacks(&focused) {
+        assert!(stack.last().unwrap().contains("simple::main"));
+    }
+
+    let ignored = run_json(&["--ignore", r"simple::main\b"]);
+    for stack in stacks(&ignored) {
+        assert!(!stack.iter().any(|f| f.contains("simple::main")));
+    }
+
+    // Hiding allocator entry points attributes allocations to their callers
+    let hidden = run_json(&["--hide", "^__rust"]);
+    for allocation in hidden["allocations"].as_array().unwrap() {
+        assert!(!allocation["function"].as_str().unwrap().contains("__rust"));
+    }
+}
+
+#[test]
+fn test_stack_depth_and_skip_rules() {
+    let run_stacks = |extra: &[&str]| -> Vec<Vec<String>> {
+        let mut args = vec![
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "-o",
+            "json",
+            "-vvv",
+        ];
+        args.extend_from_slice(extra);
+        args.extend_from_slice(&["run", "--example", "simple"]);
+        let output = Command::new("cargo")
+            .args(&args)
+            .output()
+            .expect("Failed to execute command");
+        assert!(output.status.success(), "Command should succeed");
+        let json: serde_json::Value =
+            serde_json::from_str(&String::from_utf8_lossy(&output.stdout))
+                .expect("Output should be JSON");
+        json["allocations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|a| serde_json::from_value(a["stack_trace"].clone()).unwrap())
+            .collect()
+    };
+
+    // Standard library allocation internals are skipped by default
+    let stacks = run_stacks(&[]);
+    assert!(!stacks.is_empty());
+    for stack in &stacks {
+        assert!(!stack.iter().any(|f| {
+            let path = f.trim_start_matches('‹');
+            path.starts_with("alloc::") || path.starts_with("alloc[")
+        }));
+    }
+
+    for stack in run_stacks(&["--max-depth", "2"]) {
+        assert!(stack.len() <= 2, "Stacks should be cut at the max depth");
+    }
+
+    // Skip patterns match whole path segments
+    let stacks = run_stacks(&["--skip-frames", "simple", "--skip-frames", "sim"]);
+    assert!(!stacks.is_empty());
+    for stack in &stacks {
+        assert!(!stack.iter().any(|f| f.starts_with("simple::")));
+    }
+    let stacks = run_stacks(&["--skip-frames", "sim"]);
+    assert!(stacks.iter().flatten().any(|f| f.starts_with("simple::")));
+
+    // Hide the allocator shims so kept frames become the reported sites
+    let stacks = run_stacks(&["--keep-frames", "alloc::fmt", "--hide", "^__rust"]);
+    assert!(stacks.iter().any(|s| s[0].contains("::fmt::format")));
+}
+
+#[test]
+fn test_config_file_defaults() {
+    let temp_dir = TempDir::new().expect("Failed to create temp dir");
+    let config_path = temp_dir.path().join(".alloc-profile.toml");
+    std::fs::write(
+        &config_path,
+        "output = \"json\"\nverbose = 1\nsort-by = \"size\"\nlimit = 1\n",
+    )
+    .unwrap();
+
+    let run = |extra: &[&str]| {
+        let mut args = vec!["run", "--quiet", "--", "alloc-profile", "--config"];
+        args.push(config_path.to_str().unwrap());
+        args.extend_from_slice(extra);
+        args.extend_from_slice(&["run", "--example", "simple"]);
+        Command::new("cargo")
+            .args(&args)
+            .output()
+            .expect("Failed to execute command")
+    };
+
+    let output = run(&[]);
+    assert!(output.status.success(), "Command should succeed");
+    let json: serde_json::Value =
+        serde_json::from_str(&String::from_utf8_lossy(&output.stdout)).expect("Should be JSON");
+    let allocations = json["allocations"].as_array().unwrap();
+    assert_eq!(allocations.len(), 1, "Limit should come from the config");
+    assert!(allocations[0]["total_bytes"].is_u64());
+
+    // Flags override the file
+    let output = run(&["--limit", "2"]);
+    let json: serde_json::Value =
+        serde_json::from_str(&String::from_utf8_lossy(&output.stdout)).expect("Should be JSON");
+    assert_eq!(json["allocations"].as_array().unwrap().len(), 2);
+
+    std::fs::write(&config_path, "sort_by = \"size\"\n").unwrap();
+    let output = run(&[]);
+    assert!(!output.status.success(), "Unknown keys should be rejected");
+}
+
+#[test]
+fn test_group_by_crate() {
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "-o",
+            "json",
+            "-v",
+            "--group-by",
+            "crate",
+            "run",
+            "--example",
+            "simple",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Command should succeed");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let json: serde_json::Value = serde_json::from_str(&stdout).expect("Output should be JSON");
+
+    // The example's allocations go through std but belong to this package
+    let allocations = json["allocations"].as_array().unwrap();
+    let package = allocations
+        .iter()
+        .find(|a| a["function"] == format!("cargo-alloc-profile v{}", env!("CARGO_PKG_VERSION")))
+        .expect("Should attribute allocations to the package");
+    // Only std's own runtime allocations, which never leave std, stay there
+    assert!(package["count"].as_u64().unwrap() >= 62);
+    assert!(
+        !allocations.iter().any(|a| a["function"] == "alloc"),
+        "Standard library crates should be skipped over"
+    );
+}
+
+#[test]
+fn test_workspace_attribution() {
+    let temp_dir = TempDir::new().expect("Failed to create temp dir");
+    let baseline_path = temp_dir.path().join("baseline.json");
+
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "-o",
+            "json",
+            "--attribute",
+            "workspace",
+            "--filter",
+            "simple::main",
+            "--save",
+            baseline_path.to_str().unwrap(),
+            "run",
+            "--example",
+            "simple",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Command should succeed");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let json: serde_json::Value = serde_json::from_str(&stdout).expect("Output should be JSON");
+
+    // Allocations made through std are charged to the example's code
+    let allocations = json["allocations"].as_array().unwrap();
+    assert_eq!(allocations.len(), 1, "Everything should group under main");
+    assert_eq!(allocations[0]["function"], "simple::main");
+    assert!(allocations[0]["count"].as_u64().unwrap() >= 50);
+
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "--attribute",
+            "workspace",
+            "--compare",
+            baseline_path.to_str().unwrap(),
+            "run",
+            "--example",
+            "simple",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Compare should succeed");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let main_line = stdout
+        .lines()
+        .find(|l| l.contains("simple::main"))
+        .expect("Comparison should include main");
+    assert!(
+        !main_line.contains("NEW"),
+        "The baseline should be attributed the same way"
+    );
+}
+
+#[test]
+fn test_group_by_type() {
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "-o",
+            "json",
+            "--group-by",
+            "type",
+            "run",
+            "--example",
+            "simple",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Command should succeed");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let json: serde_json::Value = serde_json::from_str(&stdout).expect("Output should be JSON");
+    let allocations = json["allocations"].as_array().unwrap();
+
+    // The example formats 50 strings and grows several vectors
+    let strings = allocations
+        .iter()
+        .find(|a| a["function"] == "String")
+        .expect("Should report String allocations");
+    assert_eq!(strings["count"], 50);
+    assert!(
+        allocations
+            .iter()
+            .any(|a| a["function"].as_str().unwrap().starts_with("Vec<")),
+        "Should report Vec allocations with their element type"
+    );
+}
+
+#[test]
+fn test_group_by_span() {
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "-o",
+            "json",
+            "--group-by",
+            "span",
+            "run",
+            "--example",
+            "tracing_spans",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Command should succeed");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let json: serde_json::Value = serde_json::from_str(&stdout).expect("Output should be JSON");
+    let allocations = json["allocations"].as_array().unwrap();
+
+    // Nested spans are reported by their full path
+    let count = |span: &str| {
+        allocations
+            .iter()
+            .find(|a| a["function"] == span)
+            .map(|a| a["count"].as_u64().unwrap())
+            .unwrap_or(0)
+    };
+    assert!(
+        count("request > parse") >= 20,
+        "Should report the parse span"
+    );
+    assert!(
+        count("request > render") >= 20,
+        "Should report the render span"
+    );
+    assert!(
+        !allocations.iter().any(|a| a["function"] == "request"),
+        "Allocations inside nested spans should not count for the outer span"
+    );
+}
+
+#[test]
+fn test_symbol_styles() {
+    let run_stacks = |style: &str| -> Vec<String> {
+        let output = Command::new("cargo")
+            .args([
+                "run",
+                "--quiet",
+                "--",
+                "alloc-profile",
+                "-o",
+                "json",
+                "-vvv",
+                "--keep-frames",
+                "alloc",
+                "--symbols",
+                style,
+                "run",
+                "--example",
+                "simple",
+            ])
+            .output()
+            .expect("Failed to execute command");
+        assert!(output.status.success(), "Command should succeed");
+        let json: serde_json::Value =
+            serde_json::from_str(&String::from_utf8_lossy(&output.stdout))
+                .expect("Output should be JSON");
+        json["allocations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|a| a["stack_trace"].as_array().unwrap().clone())
+            .map(|f| f.as_str().unwrap().to_string())
+            .collect()
+    };
+
+    for frame in run_stacks("no-generics") {
+        let function = frame.split(" (").next().unwrap();
+        assert!(!function.contains("{{closure}}") && !function.contains("{closure#"));
+        assert!(
+            !function.contains("alloc["),
+            "Crate hashes should be removed"
+        );
+        let mut chars = function.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c.is_alphanumeric() {
+                assert_ne!(chars.peek(), Some(&'‹'), "Generics left in {}", function);
+            }
+        }
+    }
+
+    let short = run_stacks("short");
+    assert!(
+        short
+            .iter()
+            .any(|f| f.starts_with("Vec::") || f.starts_with("RawVec"))
+    );
+    assert!(!short.iter().any(|f| f.starts_with("alloc::vec::")));
+    assert!(short.iter().any(|f| f.starts_with("simple::main")));
+}
+
+#[test]
+fn test_exec_preload() {
+    let build = Command::new("cargo")
+        .args(["build", "--quiet", "-p", "cargo-alloc-profile-preload"])
+        .status()
+        .expect("Failed to build preload library");
+    assert!(build.success(), "Preload library should build");
+
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "--output",
+            "json",
+            "exec",
+            "--",
+            "ls",
+            "/",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Command should succeed");
+    let json: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&output.stdout))
+        .expect("Output should be JSON");
+    assert!(json["summary"]["total_allocations"].as_u64().unwrap() > 0);
+    assert!(
+        json["allocations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|a| a["function"] == "malloc"),
+        "C allocations should be recorded"
+    );
+}
+
+#[test]
+fn test_exec_builds_preload() {
+    // An installed executable has no interposer next to it
+    let temp_dir = TempDir::new().expect("Failed to create temp dir");
+    let exe = temp_dir.path().join("cargo-alloc-profile");
+    std::fs::copy(env!("CARGO_BIN_EXE_cargo-alloc-profile"), &exe).unwrap();
+
+    let output = Command::new(&exe)
+        .args(["alloc-profile", "--output", "json", "exec", "--", "ls", "/"])
+        .env_remove("CARGO_ALLOC_PROFILE_PRELOAD")
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Command should succeed");
+    let json: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&output.stdout))
+        .expect("Output should be JSON");
+    assert!(json["summary"]["total_allocations"].as_u64().unwrap() > 0);
+}
+
+#[test]
+fn test_zero_setup_injection() {
+    // A project that knows nothing about the profiler
+    let temp_dir = TempDir::new().expect("Failed to create temp dir");
+    let project = temp_dir.path();
+    std::fs::create_dir(project.join("src")).unwrap();
+    std::fs::write(
+        project.join("Cargo.toml"),
+        "[package]\nname = \"plain\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[workspace]\n",
+    )
+    .unwrap();
+    std::fs::write(
+        project.join("src/main.rs"),
+        "//! Plain program\nmod util;\n\nfn main() {\n    println!(\"{}\", util::make().len());\n}\n",
+    )
+    .unwrap();
+    std::fs::write(
+        project.join("src/util.rs"),
+        "pub fn make() -> Vec<String> {\n    (0..100).map(|i| format!(\"item {}\", i)).collect()\n}\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-alloc-profile"))
+        .args([
+            "alloc-profile",
+            "--output",
+            "json",
+            "--hide",
+            "^__rust|^core::",
+            "run",
+        ])
+        .current_dir(project)
+        // Share the shim build between runs of the test suite
+        .env(
+            "CARGO_TARGET_DIR",
+            std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("zero-setup"),
+        )
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Command should succeed");
+    let json: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&output.stdout))
+        .expect("Output should be JSON");
+    let count: u64 = json["allocations"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|a| {
+            a["function"]
+                .as_str()
+                .unwrap()
+                .starts_with("plain::util::make")
+        })
+        .map(|a| a["count"].as_u64().unwrap())
+        .sum();
+    assert!(
+        count > 100,
+        "Allocations should be attributed to the project's code"
+    );
+
+    let leftovers: Vec<_> = std::fs::read_dir(project.join("src"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(
+        leftovers.len(),
+        2,
+        "Generated crate roots should be removed"
+    );
+}
+
+#[test]
+fn test_injection_into_tests_for_target() {
+    // `cargo test` compiles `src/main.rs` as the binary and as its unit tests
+    // at the same time
+    let temp_dir = TempDir::new().expect("Failed to create temp dir");
+    let project = temp_dir.path();
+    std::fs::create_dir(project.join("src")).unwrap();
+    std::fs::write(
+        project.join("Cargo.toml"),
+        "[package]\nname = \"tested\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[workspace]\n",
+    )
+    .unwrap();
+    std::fs::write(
+        project.join("src/main.rs"),
+        "fn make() -> Vec<u8> {\n    vec![1, 2, 3]\n}\n\nfn main() {\n    println!(\"{}\", make().len());\n}\n\n#[test]\nfn makes_three() {\n    assert_eq!(make().len(), 3);\n}\n",
+    )
+    .unwrap();
+
+    let rustc = Command::new("rustc")
+        .arg("-vV")
+        .output()
+        .expect("Failed to run rustc");
+    let host = String::from_utf8_lossy(&rustc.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("host: ").map(str::to_string))
+        .expect("rustc should print its host");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-alloc-profile"))
+        .args(["alloc-profile", "test", "--", "--target", &host])
+        .current_dir(project)
+        .env(
+            "CARGO_TARGET_DIR",
+            std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("zero-setup"),
+        )
+        .output()
+        .expect("Failed to execute command");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "Command should succeed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("test result: ok. 1 passed"));
+    assert!(stdout.contains("Allocation Profile"));
+
+    let leftovers: Vec<_> = std::fs::read_dir(project.join("src"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(
+        leftovers.len(),
+        1,
+        "Generated crate roots should be removed"
+    );
+}
+
+#[test]
+fn test_injection_skips_own_allocator() {
+    // A project with its own global allocator cannot link the profiler's
+    let temp_dir = TempDir::new().expect("Failed to create temp dir");
+    let project = temp_dir.path();
+    std::fs::create_dir(project.join("src")).unwrap();
+    std::fs::write(
+        project.join("Cargo.toml"),
+        "[package]\nname = \"own_alloc\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[workspace]\n",
+    )
+    .unwrap();
+    std::fs::write(
+        project.join("src/main.rs"),
+        "#[global_allocator]\nstatic ALLOC: std::alloc::System = std::alloc::System;\n\nfn main() {\n    println!(\"{}\", vec![0u8; 64].len());\n}\n",
+    )
+    .unwrap();
+
+    let run = |extra: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_cargo-alloc-profile"))
+            .arg("alloc-profile")
+            .args(extra)
+            .arg("run")
+            .current_dir(project)
+            .env(
+                "CARGO_TARGET_DIR",
+                std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("own-alloc"),
+            )
+            .output()
+            .expect("Failed to execute command")
+    };
+
+    let output = run(&[]);
+    assert!(output.status.success(), "Command should succeed");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("own_alloc sets its own global allocator"));
+    assert!(stderr.contains("No profiling data was generated"));
+
+    // Without injection the project is built as it is
+    let output = run(&["--no-inject"]);
+    assert!(output.status.success(), "Command should succeed");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("global allocator"));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("64"));
+}
+
+#[test]
+fn test_criterion_benchmark_table() {
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "--output",
+            "json",
+            "bench",
+            "--",
+            "--features",
+            "criterion",
+            "--bench",
+            "allocations",
+            "--",
+            "--warm-up-time",
+            "0.1",
+            "--measurement-time",
+            "0.1",
+            "--sample-size",
+            "10",
+            "vec_",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Command should succeed");
+    let json: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&output.stdout))
+        .expect("Output should be JSON");
+    let benchmarks = json["benchmarks"]
+        .as_array()
+        .expect("Should list benchmarks");
+    let per_iteration = |id: &str| {
+        benchmarks
+            .iter()
+            .find(|b| b["id"] == id)
+            .unwrap_or_else(|| panic!("Missing benchmark {}", id))["allocations_per_iteration"]
+            .as_f64()
+            .unwrap()
+    };
+
+    assert_eq!(per_iteration("vec_with_capacity"), 1.0);
+    assert!(per_iteration("vec_push") > 1.0, "Growth should reallocate");
+    assert!(
+        !benchmarks.iter().any(|b| b["id"] == "format"),
+        "Filtered out benchmarks should not be listed"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_stream_protocol() {
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixListener;
+    use std::time::{Duration, Instant};
+
+    let dir = TempDir::new().unwrap();
+    let socket = dir.path().join("profile.sock");
+    let listener = UnixListener::bind(&socket).unwrap();
+    listener.set_nonblocking(true).unwrap();
+
+    let mut child = Command::new("cargo")
+        .args(["run", "--quiet", "--example", "simple"])
+        .env("CARGO_ALLOC_PROFILE", "1")
+        .env("CARGO_ALLOC_PROFILE_SOCKET", &socket)
+        .env("CARGO_ALLOC_PROFILE_INTERVAL_MS", "10")
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .expect("Failed to execute command");
+
+    // The example connects once profiling is enabled
+    let deadline = Instant::now() + Duration::from_secs(300);
+    let stream = loop {
+        match listener.accept() {
+            Ok((stream, _)) => break stream,
+            Err(_) if Instant::now() < deadline => {
+                assert!(
+                    child.try_wait().unwrap().is_none(),
+                    "Example exited without connecting"
+                );
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            Err(e) => panic!("Example never connected: {}", e),
+        }
+    };
+    stream.set_nonblocking(false).unwrap();
+
+    let messages: Vec<serde_json::Value> = BufReader::new(stream)
+        .lines()
+        .map(|line| serde_json::from_str(&line.unwrap()).expect("Each line should be JSON"))
+        .collect();
+    assert!(child.wait().unwrap().success(), "Example should succeed");
+
+    let first = messages.first().expect("Should stream messages");
+    assert_eq!(first["start"]["pid"], child.id());
+
+    // `write_report` ends the stream with the final totals
+    let last = &messages.last().unwrap()["finish"];
+    assert!(last["total_allocations"].as_u64().unwrap() >= 50);
+
+    // Sites are sent with the updates in which they change
+    let sites: usize = messages[1..]
+        .iter()
+        .map(|message| {
+            let delta = message.get("update").unwrap_or(&message["finish"]);
+            delta["sites"].as_array().unwrap().len()
+        })
+        .sum();
+    assert!(sites > 0, "Should send allocation sites");
+}
+
+#[test]
+fn test_report_without_write_report() {
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "-o",
+            "json",
+            "run",
+            "--example",
+            "unfinished",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Command should succeed");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("ended without writing its report"),
+        "Should warn that the report is incomplete: {}",
+        stderr
+    );
+
+    // The report is sent on exit with the allocations made before it
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let json: serde_json::Value = serde_json::from_str(&stdout).expect("Output should be JSON");
+    assert!(json["summary"]["total_allocations"].as_u64().unwrap() >= 100);
+    assert_eq!(json["partial"], true, "Report should be marked partial");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_process_tree() {
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "-o",
+            "json",
+            "run",
+            "--example",
+            "processes",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success(), "Command should succeed");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let json: serde_json::Value = serde_json::from_str(&stdout).expect("Output should be JSON");
+
+    // The example, the child it forks and the worker it runs
+    let processes = json["processes"].as_array().expect("Should list processes");
+    assert_eq!(processes.len(), 3, "{:?}", processes);
+    let parent = &processes[0];
+    for child in &processes[1..] {
+        assert_eq!(child["parent_pid"], parent["pid"]);
+    }
+
+    // The forked child does not inherit the allocations of its parent
+    let allocations = |process: &serde_json::Value| process["total_allocations"].as_u64().unwrap();
+    assert!(allocations(parent) >= 100);
+    assert!(processes[1..].iter().all(|child| allocations(child) < 100));
+
+    let total: u64 = processes.iter().map(allocations).sum();
+    assert_eq!(
+        json["summary"]["total_allocations"].as_u64().unwrap(),
+        total
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn test_output_per_process() {
+    let dir = TempDir::new().unwrap();
+    let output = Command::new("cargo")
+        .args(["run", "--quiet", "--example", "processes"])
+        .env(
+            "CARGO_ALLOC_PROFILE_OUTPUT",
+            dir.path().join("profile-%p.json"),
+        )
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success(), "Example should succeed");
+
+    // Each process writes its own report, recording its parent
+    let reports: Vec<serde_json::Value> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| {
+            serde_json::from_slice(&std::fs::read(entry.unwrap().path()).unwrap()).unwrap()
+        })
+        .collect();
+    assert_eq!(reports.len(), 3);
+    for report in &reports {
+        assert!(report["pid"].as_u64().unwrap() > 0);
+        assert!(report["parent_pid"].as_u64().unwrap() > 0);
+    }
+    let parents = reports
+        .iter()
+        .filter(|report| {
+            reports
+                .iter()
+                .any(|other| other["parent_pid"] == report["pid"])
+        })
+        .count();
+    assert_eq!(parents, 1, "Both children should share a parent");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_partial_report_on_crash() {
+    use std::os::unix::process::ExitStatusExt;
+
+    for mode in ["panic", "terminate"] {
+        let dir = TempDir::new().unwrap();
+        let report = dir.path().join("profile.json");
+        let output = Command::new("cargo")
+            .args(["run", "--quiet", "--example", "crash", "--", mode])
+            .env("CARGO_ALLOC_PROFILE_OUTPUT", &report)
+            .env_remove("RUST_BACKTRACE")
+            .output()
+            .expect("Failed to execute command");
+
+        // The example still ends as it would have without the profiler
+        match mode {
+            "panic" => assert_eq!(output.status.code(), Some(101)),
+            _ => assert_eq!(output.status.signal(), Some(15)),
+        }
+
+        let json: serde_json::Value = serde_json::from_slice(
+            &std::fs::read(&report).unwrap_or_else(|_| panic!("{} should write a report", mode)),
+        )
+        .unwrap();
+        assert_eq!(json["partial"], true, "{} report should be partial", mode);
+        assert!(json["total_allocations"].as_u64().unwrap() >= 100);
+    }
+}
+
+#[test]
+fn test_panic_backtrace_is_not_profiled() {
+    use std::time::{Duration, Instant};
+
+    // Build first, so only the program itself is timed
+    let status = Command::new("cargo")
+        .args(["build", "--quiet", "--example", "crash"])
+        .status()
+        .expect("Failed to build the example");
+    assert!(status.success());
+
+    // Printing the backtrace symbolizes it, which allocates heavily
+    let dir = TempDir::new().unwrap();
+    let report = dir.path().join("profile.json");
+    let mut child = Command::new("cargo")
+        .args(["run", "--quiet", "--example", "crash", "--", "panic"])
+        .env("CARGO_ALLOC_PROFILE_OUTPUT", &report)
+        .env("RUST_BACKTRACE", "1")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .expect("Failed to execute command");
+
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if Instant::now() > deadline {
+            let _ = child.kill();
+            panic!("The example should exit promptly after panicking");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    assert_eq!(status.code(), Some(101));
+
+    let json: serde_json::Value = serde_json::from_slice(&std::fs::read(&report).unwrap()).unwrap();
+    assert!(
+        json["total_allocations"].as_u64().unwrap() < 1000,
+        "The backtrace should not be recorded: {}",
+        json["total_allocations"]
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn test_report_after_interrupt() {
+    use std::io::{BufRead, BufReader, Read};
+    use std::os::unix::process::CommandExt;
+    use std::process::Stdio;
+
+    // Build first, so the program starts soon after `cargo alloc-profile`
+    let status = Command::new("cargo")
+        .args(["build", "--quiet", "--example", "crash"])
+        .status()
+        .expect("Failed to build the example");
+    assert!(status.success());
+
+    // In a process group of its own, like a terminal's foreground job
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cargo-alloc-profile"))
+        .args(["alloc-profile", "run", "--example", "crash", "--", "wait"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .expect("Failed to execute command");
+
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    while !line.contains("Indexed 100 words") {
+        line.clear();
+        assert!(
+            stdout.read_line(&mut line).unwrap() > 0,
+            "The example should start"
+        );
+    }
+
+    // Ctrl+C interrupts the whole group
+    let status = Command::new("kill")
+        .args(["-INT", "--", &format!("-{}", child.id())])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let mut report = String::new();
+    stdout.read_to_string(&mut report).unwrap();
+    let mut stderr = String::new();
+    child
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    child.wait().unwrap();
+
+    assert!(
+        report.contains("Partial report") && report.contains("Allocation Profile"),
+        "The partial report should be printed: {}\n{}",
+        report,
+        stderr
+    );
+}
+
+#[test]
+fn test_report_when_program_fails() {
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "alloc-profile",
+            "-o",
+            "json",
+            "run",
+            "--example",
+            "crash",
+            "--",
+            "panic",
+        ])
+        .env_remove("RUST_BACKTRACE")
+        .output()
+        .expect("Failed to execute command");
+
+    // The command exits as the program did, after reporting
+    assert_eq!(output.status.code(), Some(101));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Command failed"),
+        "Should report the failure: {}",
+        stderr
+    );
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let json: serde_json::Value = serde_json::from_str(&stdout).expect("Output should be JSON");
+    assert!(json["summary"]["total_allocations"].as_u64().unwrap() >= 100);
+}